@@ -0,0 +1,170 @@
+/// Lua version selected through the `lua5x` features.
+#[derive(Debug, Clone, Copy)]
+enum Version {
+    Lua51,
+    Lua52,
+    Lua53,
+    Lua54,
+    LuaJIT,
+    Luau,
+}
+
+impl Version {
+    fn selected() -> Version {
+        let enabled: Vec<Version> = [
+            (cfg!(feature = "lua51"), Version::Lua51),
+            (cfg!(feature = "lua52"), Version::Lua52),
+            (cfg!(feature = "lua53"), Version::Lua53),
+            (cfg!(feature = "lua54"), Version::Lua54),
+            (cfg!(feature = "luajit"), Version::LuaJIT),
+            (cfg!(feature = "luau"), Version::Luau),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, version)| enabled.then_some(version))
+        .collect();
+
+        match enabled[..] {
+            [version] => version,
+            [] => panic!(
+                "no Lua version selected, enable one of `lua51`, `lua52`, `lua53`, `lua54`, `luajit` or `luau`"
+            ),
+            _ => panic!(
+                "features `lua51`, `lua52`, `lua53`, `lua54`, `luajit` and `luau` are mutually exclusive"
+            ),
+        }
+    }
+
+    /// Names the version is packaged under, in the order pkg-config should try them.
+    #[cfg(feature = "system-lua")]
+    fn pkg_names(self) -> &'static [&'static str] {
+        match self {
+            Version::Lua51 => &["lua5.1", "lua-5.1", "lua51"],
+            Version::Lua52 => &["lua5.2", "lua-5.2", "lua52"],
+            Version::Lua53 => &["lua5.3", "lua-5.3", "lua53"],
+            Version::Lua54 => &["lua5.4", "lua-5.4", "lua54"],
+            Version::LuaJIT => &["luajit"],
+            Version::Luau => unreachable!(),
+        }
+    }
+
+    #[cfg(feature = "system-lua")]
+    fn range(self) -> std::ops::Range<&'static str> {
+        match self {
+            Version::Lua51 => "5.1".."5.2",
+            Version::Lua52 => "5.2".."5.3",
+            Version::Lua53 => "5.3".."5.4",
+            Version::Lua54 => "5.4".."5.5",
+            Version::LuaJIT => "5.1".."5.2",
+            Version::Luau => unreachable!(),
+        }
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(any(
+        all(feature = "vendored", feature = "system-lua"),
+        all(feature = "vendored", feature = "dynamic"),
+        all(feature = "system-lua", feature = "dynamic"),
+    ))]
+    panic!("features `vendored`, `system-lua` and `dynamic` are mutually exclusive");
+
+    #[cfg(all(feature = "luau", feature = "dynamic"))]
+    panic!("Luau cannot be loaded dynamically, use the `vendored` feature");
+
+    let version = Version::selected();
+
+    #[cfg(feature = "vendored")]
+    vendored(version);
+
+    #[cfg(feature = "system-lua")]
+    system_lua(version);
+
+    // Linking against `lua.dll`, or loading a library at runtime, only needs the
+    // version to be validated.
+    let _ = version;
+}
+
+/// Builds the bundled Lua sources and links them statically.
+#[cfg(feature = "vendored")]
+fn vendored(version: Version) {
+    // LuaJIT raises errors as foreign exceptions on most targets, which abort
+    // when they reach the `catch_unwind` around every Rust callback. Its own
+    // unwinder skips over Rust frames like longjmp does. The define only
+    // reaches the makefile through `CFLAGS`.
+    #[cfg(feature = "luajit")]
+    {
+        let _ = version;
+        let cflags = std::env::var("CFLAGS").unwrap_or_default();
+        std::env::set_var("CFLAGS", format!("{cflags} -DLUAJIT_NO_UNWIND"));
+        luajit_src::Build::new().build().print_cargo_metadata();
+    }
+
+    // Errors must be raised with longjmp like the other versions: C++ exceptions
+    // cannot travel through the Rust frames of a callback. The stack size has to
+    // match `LUAI_MAXCSTACK` in `src/lua.rs`.
+    #[cfg(feature = "luau")]
+    {
+        let _ = version;
+        luau0_src::Build::new()
+            .use_longjmp(true)
+            .set_max_cstack_size(100000)
+            .build()
+            .print_cargo_metadata();
+    }
+
+    #[cfg(any(feature = "lua51", feature = "lua52", feature = "lua53", feature = "lua54"))]
+    lua(version);
+}
+
+/// Builds the sources of Lua 5.1 to 5.4.
+#[cfg(all(feature = "vendored", any(feature = "lua51", feature = "lua52", feature = "lua53", feature = "lua54")))]
+fn lua(version: Version) {
+    let version = match version {
+        Version::Lua51 => lua_src::Lua51,
+        Version::Lua52 => lua_src::Lua52,
+        Version::Lua53 => lua_src::Lua53,
+        Version::Lua54 => lua_src::Lua54,
+        Version::LuaJIT | Version::Luau => unreachable!(),
+    };
+
+    // The panic handler of `src/lua.rs` unwinds through the Lua frames of the
+    // failed call, which needs unwind tables. MSVC always has them.
+    if std::env::var("CARGO_CFG_TARGET_ENV").as_deref() != Ok("msvc") {
+        let cflags = std::env::var("CFLAGS").unwrap_or_default();
+        std::env::set_var("CFLAGS", format!("{cflags} -funwind-tables"));
+    }
+    let artifacts = lua_src::Build::new().build(version);
+    artifacts.print_cargo_metadata();
+}
+
+/// Finds an installed Lua through pkg-config and links against it.
+///
+/// Distributions do not agree on the package name, so the usual spellings
+/// are tried in order before falling back to a plain `lua` package.
+#[cfg(feature = "system-lua")]
+fn system_lua(version: Version) {
+    if let Version::Luau = version {
+        panic!("Luau is not distributed as a system library, use the `vendored` feature");
+    }
+
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+
+    for name in version.pkg_names() {
+        if pkg_config::Config::new().probe(name).is_ok() {
+            return;
+        }
+    }
+
+    let found = pkg_config::Config::new()
+        .range_version(version.range())
+        .probe("lua");
+
+    if let Err(e) = found {
+        panic!(
+            "unable to find {version:?} through pkg-config (tried {}, lua): {e}",
+            version.pkg_names().join(", ")
+        );
+    }
+}
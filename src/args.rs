@@ -0,0 +1,75 @@
+use crate::{
+    context::{LunarContext, Value},
+    convert::FromLua,
+    lua::{lua_checkstack, raise_argument, runtime_error},
+};
+
+/// The arguments a callback was called with, see [`LunarContext::args`].
+///
+/// Positions start at 1, as in Lua. Positions of a view made by [`rest`](Args::rest)
+/// start at 1 too, while errors keep reporting the position in the whole call.
+#[derive(Debug, Clone, Copy)]
+pub struct Args<'a> {
+    ctx: &'a LunarContext,
+    /// Stack index of the first argument of the view.
+    first: i32,
+    len: i32,
+}
+
+impl<'a> Args<'a> {
+    pub(crate) fn new(ctx: &'a LunarContext) -> Args<'a> {
+        Args { ctx, first: 1, len: ctx.stack_size() }
+    }
+
+    /// Number of arguments, trailing `nil`s included.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether argument `i` is absent, as opposed to given as `nil`.
+    #[inline]
+    pub fn is_none(&self, i: usize) -> bool {
+        i == 0 || i > self.len()
+    }
+
+    /// Reads argument `i` as a `T`, same as [`LunarContext::get`]. An absent
+    /// argument converts like `nil` into an `Option`, and is an error otherwise,
+    /// as is position 0.
+    pub fn get<T: FromLua>(&self, i: usize) -> T {
+        if i == 0 {
+            raise_argument(0, runtime_error("argument positions start at 1"));
+        }
+        let arg = self.first + i as i32 - 1;
+        if !self.is_none(i) {
+            return self.ctx.get(arg);
+        }
+
+        // The callback may have pushed values past the arguments since: read the
+        // free slot above the top instead, which holds no value.
+        let absent = self.ctx.stack_size() + 1;
+        unsafe { lua_checkstack(self.ctx.L(), 1) };
+        match T::from_lua(self.ctx, absent) {
+            Ok(value) => value,
+            Err(error) => raise_argument(arg, error),
+        }
+    }
+
+    /// The arguments from position `from` on, e.g. the values of a format
+    /// string with `args.rest(2)`. Empty if there are fewer.
+    pub fn rest(&self, from: usize) -> Args<'a> {
+        let skip = from.saturating_sub(1).min(self.len()) as i32;
+        Args { ctx: self.ctx, first: self.first + skip, len: self.len - skip }
+    }
+
+    /// The arguments as [`Value`]s, see [`LunarContext::get_value`].
+    pub fn iter(&self) -> impl Iterator<Item = Value> + 'a {
+        let ctx = self.ctx;
+        (self.first..self.first + self.len).map(move |idx| ctx.get_value(idx))
+    }
+}
@@ -3,16 +3,49 @@
 #![allow(non_camel_case_types)]
 
 use libc::c_void;
-use std::mem::size_of;
+use std::{ffi::CStr, mem::size_of};
 
 use crate::{
+    args::Args,
+    convert::{mismatch, FromLua, IntoLuaMulti},
+    error::{Frame, LunarError},
+    function::LuaFunction,
     lua::*,
     metatable::MetaTable,
+    refr::LuaRef,
     table::Table,
-    types::{LuaType, Type},
+    types::{LuaType, Number, Type},
 };
 
 pub type Function = fn(LunarContext) -> i32;
+
+/// What a fallible Rust callback may return: the number of values it pushed, or
+/// nothing for none. Behind a `Result`, the error is raised in Lua, where `pcall`
+/// can catch it.
+///
+/// [`Function`] is the plain callback, returning the count as is.
+pub trait CallbackResult {
+    fn into_result(self) -> Result<i32, LunarError>;
+}
+
+impl CallbackResult for i32 {
+    fn into_result(self) -> Result<i32, LunarError> {
+        Ok(self)
+    }
+}
+
+impl CallbackResult for () {
+    fn into_result(self) -> Result<i32, LunarError> {
+        Ok(0)
+    }
+}
+
+impl<E: Into<LunarError>> CallbackResult for Result<i32, E> {
+    fn into_result(self) -> Result<i32, LunarError> {
+        self.map_err(Into::into)
+    }
+}
+
 const STACK_MAX: i32 = 8;
 
 pub struct Userdata<T> {
@@ -41,7 +74,53 @@ impl<T> Userdata<T> {
     }
 }
 
-#[derive(Debug, Clone)]
+/// A handle on a full userdata read from Lua, kept alive through the registry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnyUserdata {
+    luaref: LuaRef,
+}
+
+impl AnyUserdata {
+    /// The `__name` field of its metatable, as set for types registered with
+    /// [`Lunar::register_userdata`](crate::lunar::Lunar::register_userdata).
+    pub fn name(&self) -> Option<String> {
+        let L = self.luaref.L();
+        unsafe {
+            let userdata = self.luaref.push_reference();
+            let mut name = None;
+            if lua_getmetatable(L, userdata) != 0 {
+                lua_getfield(L, -1, c"__name".as_ptr() as const_char);
+                if lua_type(L, -1) == LUA_TSTRING {
+                    name = Some(CStr::from_ptr(lua_tolstring(L, -1, std::ptr::null_mut())).to_string_lossy().into_owned());
+                }
+                lua_pop(L, 2);
+            }
+            lua_pop(L, 1);
+            name
+        }
+    }
+
+    #[inline]
+    pub(crate) fn push_userdata(&self, L: lua_State) -> i32 {
+        self.luaref.push_onto(L)
+    }
+}
+
+/// A handle on a Lua thread (coroutine), kept alive through the registry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thread {
+    luaref: LuaRef,
+}
+
+impl Thread {
+    #[inline]
+    pub(crate) fn push_thread(&self, L: lua_State) -> i32 {
+        self.luaref.push_onto(L)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(unpredictable_function_pointer_comparisons)]
 pub enum Value {
     Nil,
     Bool(bool),
@@ -51,12 +130,19 @@ pub enum Value {
     Long(i64),
     Float(f64),
     String(String),
+    /// A Lua string kept as raw bytes, for binary data or text that is not UTF-8.
+    Bytes(Vec<u8>),
     Userdata(&'static str, *mut c_void, usize),
     LightUserdata(&'static str, *mut c_void),
     Table(Table),
+    /// A function from Lua, see [`LunarContext::get_value`].
+    LuaFunction(LuaFunction),
+    /// A full userdata from Lua, see [`LunarContext::get_value`].
+    AnyUserdata(AnyUserdata),
+    Thread(Thread),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LunarContext(lua_State);
 
 impl LunarContext {
@@ -75,141 +161,271 @@ impl LunarContext {
         unsafe {
             match value {
                 Value::Nil => lua_pushnil(self.0),
-                Value::Bool(b) => lua_pushboolean(self.0, b),
-                Value::Function(f) => push_function(self.0, f as *const ()),
-                Value::Int(i) => lua_pushinteger(self.0, i.into()),
-                Value::Long(i) => lua_pushinteger(self.0, i.into()),
+                Value::Bool(b) => lua_pushboolean(self.0, b as i32),
+                Value::Function(f) => push_function(self.0, f),
+                Value::Int(i) => lua_pushinteger(self.0, i as lua_Integer),
+                Value::Long(i) => push_long(self.0, i),
                 Value::Float(f) => lua_pushnumber(self.0, f),
-                Value::String(s) => lua_pushstring(self.0, to_const_char(s)),
+                Value::String(s) => lua_pushlstring(self.0, s.as_ptr() as const_char, s.len()),
+                Value::Bytes(b) => lua_pushlstring(self.0, b.as_ptr() as const_char, b.len()),
                 Value::Userdata(name, ptr, size) => self.push_userdata(name, ptr, size),
                 Value::LightUserdata(name, ptr) => self.push_light_userdata(name, ptr),
-                Value::Table(table) => { table.push_table(); }
-                Value::Uint(u) => lua_pushinteger(self.0, u.into()),
+                Value::Table(table) => { table.push_table(self.0); }
+                Value::Uint(u) => push_long(self.0, u.into()),
+                Value::LuaFunction(function) => { function.push_function(self.0); }
+                Value::AnyUserdata(userdata) => { userdata.push_userdata(self.0); }
+                Value::Thread(thread) => { thread.push_thread(self.0); }
             }
         }
     }
 
+    /// Pushes the values a callback returns, and gives their count for it to
+    /// return: `ctx.returns(42)`, or `ctx.returns((true, "ok"))` for several.
+    /// Every element of a tuple is returned, `None` as `nil`, even at the end.
     #[inline]
-    pub fn returns(&self, value: Value) -> i32 {
-        self.push(value);
-        return 1;
+    pub fn returns<T: IntoLuaMulti>(&self, values: T) -> i32 {
+        values.push_into_multi(self)
     }
 
-    pub fn get_boolean(&self, arg: i32) -> bool {
-        unsafe {
-            luaL_argexpected(self.0, self.get_type(arg) == LuaType::Bool, arg, "bool");
-            lua_toboolean(self.0, arg)
+    /// Same as [`returns`](LunarContext::returns) for a number of values only
+    /// known at runtime: `ctx.returns_many(vec![Value::Bool(false), Value::Nil])`.
+    pub fn returns_many(&self, values: impl IntoIterator<Item = Value>) -> i32 {
+        let mut count = 0;
+        for value in values {
+            if unsafe { lua_checkstack(self.0, 1) } == 0 {
+                raise("stack overflow (too many results)");
+            }
+            self.push(value);
+            count += 1;
         }
+        count
+    }
+
+    /// Reads an optional argument: `None` if it is `nil` or absent. Any other
+    /// value must convert to a `T`, or raises the same error as [`get`](LunarContext::get).
+    pub fn get_opt<T: FromLua>(&self, arg: i32) -> Option<T> {
+        self.get::<Option<T>>(arg)
+    }
+
+    /// Same as [`get_opt`](LunarContext::get_opt), with `default` for `nil` or absent.
+    pub fn get_or<T: FromLua>(&self, arg: i32, default: T) -> T {
+        self.get_opt(arg).unwrap_or(default)
+    }
+
+    /// The arguments of the callback, for functions taking any number of them.
+    /// Call it before pushing anything, as everything on the stack counts.
+    #[inline]
+    pub fn args(&self) -> Args<'_> {
+        Args::new(self)
+    }
+
+    /// Reads argument `arg` as a `T`. A value that does not convert raises the
+    /// usual `bad argument` error, naming the callback and the argument.
+    pub fn get<T: FromLua>(&self, arg: i32) -> T {
+        match T::from_lua(self, arg) {
+            Ok(value) => value,
+            Err(error) => raise_argument(arg, error),
+        }
+    }
+
+    /// Raises the `bad argument` error of [`get`](LunarContext::get) if argument
+    /// `arg` is not of type `ty`, naming the type `expected`.
+    fn check_arg(&self, arg: i32, ty: LuaType, expected: &str) {
+        if self.get_type(arg) != ty {
+            raise_argument(arg, mismatch(self, arg, expected));
+        }
+    }
+
+    pub fn get_boolean(&self, arg: i32) -> bool {
+        self.check_arg(arg, LuaType::Bool, "bool");
+        unsafe { lua_toboolean(self.0, arg) != 0 }
     }
 
     pub fn get_int<T>(&self, arg: i32) -> T
     where
         T: Type + From<i8> + From<i16> + From<i32>,
     {
-        luaL_argexpected(self.0, self.get_type(arg) == LuaType::Number, arg, "int");
-        luaL_checkint(self.0, arg).into()
+        self.check_arg(arg, LuaType::Number, "int");
+        self.get::<i32>(arg).into()
     }
 
     pub fn get_uint<T>(&self, arg: i32) -> T
     where
         T: Type + From<u8> + From<u16> + From<u32>,
     {
-        luaL_argexpected(self.0, self.get_type(arg) == LuaType::Number, arg, "uint");
-        luaL_checkunsigned(self.0, arg).into()
+        self.check_arg(arg, LuaType::Number, "uint");
+        self.get::<u32>(arg).into()
     }
 
     pub fn get_float<T>(&self, arg: i32) -> T
     where
         T: Type + From<f64>,
     {
-        unsafe {
-            luaL_argexpected(self.0, self.get_type(arg) == LuaType::Number, arg, "float");
-            luaL_checknumber(self.0, arg).into()
-        }
+        self.check_arg(arg, LuaType::Number, "float");
+        self.get::<f64>(arg).into()
     }
 
     pub fn get_long(&self, arg: i32) -> i64 {
-        unsafe {
-            luaL_argexpected(self.0, self.get_type(arg) == LuaType::Number, arg, "long");
-            luaL_checkinteger(self.0, arg).into()
-        }
-        
+        self.check_arg(arg, LuaType::Number, "long");
+        self.get::<i64>(arg)
+    }
+
+    /// Reads a number argument as is, an integer or a float. Unlike the other
+    /// numeric getters, it ignores the [`NumberConversion`](crate::types::NumberConversion).
+    pub fn get_number(&self, arg: i32) -> Number {
+        self.check_arg(arg, LuaType::Number, "number");
+        to_number(self.0, arg)
+    }
+
+    /// Whether the value at `idx` is a number with the integer subtype, which
+    /// exists from Lua 5.3 on. Always `false` before.
+    pub fn is_integer(&self, idx: i32) -> bool {
+        self.get_type(idx) == LuaType::Number && matches!(to_number(self.0, idx), Number::Integer(_))
     }
 
     pub fn get_userdata<T>(&self, arg: i32) -> Box<T> {
         unsafe {
             let ptr = lua_getuserdata(self.0, arg) as *mut T;
-            let value: Box<T> = Box::from_raw(ptr);
-            return value;
+            Box::from_raw(ptr)
         }
     }
 
+    #[allow(clippy::mut_from_ref)]
     pub fn get_light_userdata<T>(&self, arg: i32) -> Option<&mut T> {
         let ptr = lua_get_lightuserdata(self.0, arg) as *mut T;
         unsafe {
-            return ptr.as_mut();
+            ptr.as_mut()
         }
     }
 
     pub fn check_userdata<T>(&self, arg: i32, tname: &str) -> Box<T> {
-        unsafe {
-            let ptr = lua_check_udata(self.0, arg, tname) as *mut T;
-            let value: Box<T> = Box::from_raw(ptr);
-            return value;
+        match test_udata(self.0, arg, tname) {
+            Some(ptr) => unsafe { Box::from_raw(ptr as *mut T) },
+            None => raise_argument(arg, mismatch(self, arg, tname)),
         }
     }
 
     pub fn get_string(&self, arg: i32) -> Result<String, LunarError> {
+        self.check_arg(arg, LuaType::String, "string");
+        unsafe { to_string(lua_tolstring(self.0, arg, std::ptr::null_mut())) }
+    }
+
+    /// Reads a string argument as raw bytes, embedded zeros and non-UTF-8 data
+    /// included.
+    pub fn get_bytes(&self, arg: i32) -> Vec<u8> {
+        self.check_arg(arg, LuaType::String, "string");
         unsafe {
-            luaL_argexpected(self.0, self.get_type(arg) == LuaType::String, arg, "string");
-            let string = luaL_checklstring(self.0, arg, std::ptr::null_mut());
-            to_string(string)
+            let mut len = 0;
+            let ptr = lua_tolstring(self.0, arg, &mut len);
+            std::slice::from_raw_parts(ptr as *const u8, len).to_vec()
         }
     }
 
-    #[inline]
-    pub(crate) fn get_global(&self, name: &str) {
+    /// Raises a Lua error from a Rust callback. It is reported as
+    /// [`LunarError::Callback`], located at the Lua code that called the callback.
+    ///
+    /// Like `lua_error`, this never returns. Whatever the callback owns is
+    /// dropped on the way out. A callback made of this call alone needs its
+    /// return type spelled out: `|ctx| -> i32 { ctx.error("...") }`.
+    pub fn error(&self, message: impl Into<String>) -> ! {
+        raise(message)
+    }
+
+    /// Call stack of the running function, itself included. Handy in a message
+    /// handler set with [`Lunar::set_message_handler`](crate::lunar::Lunar::set_message_handler).
+    pub fn traceback(&self) -> Vec<Frame> {
+        traceback(self.0, 0)
+    }
+
+    /// Reads the value at `idx`, whatever its type, e.g. to accept anything
+    /// from a script and inspect it.
+    ///
+    /// Tables, functions, full userdata and threads come back as handles on the
+    /// same Lua object, kept alive through the registry. Integers are read as
+    /// [`Value::Long`] where Lua tells them apart from floats, that is from 5.3.
+    /// Strings that are not valid UTF-8 are read as [`Value::Bytes`]. No value at
+    /// all reads as `Nil`, as does a value of a type specific to Luau, such as a
+    /// vector.
+    pub fn get_value(&self, idx: i32) -> Value {
         unsafe {
-            lua_getglobal(self.0, to_const_char(name.to_string()));
+            match self.get_type(idx) {
+                LuaType::Bool => Value::Bool(lua_toboolean(self.0, idx) != 0),
+                LuaType::Number => match to_number(self.0, idx) {
+                    Number::Integer(n) => Value::Long(n),
+                    Number::Float(n) => Value::Float(n),
+                },
+                LuaType::String => {
+                    let mut len = 0;
+                    let ptr = lua_tolstring(self.0, idx, &mut len);
+                    let bytes = std::slice::from_raw_parts(ptr as *const u8, len).to_vec();
+                    match String::from_utf8(bytes) {
+                        Ok(string) => Value::String(string),
+                        Err(e) => Value::Bytes(e.into_bytes()),
+                    }
+                }
+                LuaType::Table => Value::Table(Table::from_stack(self, idx)),
+                LuaType::Function => Value::LuaFunction(LuaFunction::from_stack(self, idx)),
+                LuaType::Userdata => Value::AnyUserdata(AnyUserdata { luaref: self.reference(idx) }),
+                LuaType::LightUserdata => Value::LightUserdata("", lua_touserdata(self.0, idx)),
+                LuaType::Thread => Value::Thread(Thread { luaref: self.reference(idx) }),
+                LuaType::Nil | LuaType::Undefined => Value::Nil,
+            }
         }
     }
 
+    fn reference(&self, idx: i32) -> LuaRef {
+        unsafe { lua_pushvalue(self.0, idx) };
+        LuaRef::register_last_stack_value(self.0)
+    }
+
+    /// Pushes the global `name`, see [`protected_getglobal`].
+    #[inline]
+    pub(crate) fn get_global(&self, name: &str) -> Result<i32, LunarError> {
+        protected_getglobal(self.0, name)?;
+        Ok(self.stack_size())
+    }
+
+    /// `t[field] = v`, with `t` at `stack` and `v` on top of the stack, which is
+    /// popped. Unprotected, see [`protected_setfield`] otherwise.
     pub(crate) fn set_field(&self, field: &str, stack: i32) {
+        assert_usable(self.0);
+        let stack = absolute(self.0, stack);
         unsafe {
-            lua_setfield(self.0, stack, to_const_char(field.to_string()));
+            lua_pushlstring(self.0, field.as_ptr() as const_char, field.len());
+            lua_insert(self.0, -2);
+            lua_settable(self.0, stack);
         }
     }
 
-    pub(crate) fn get_field(&self, field: &str, stack: i32) -> i32 {
-        unsafe {
-            lua_getfield(self.0, stack, to_const_char(field.to_string()));
-            lua_gettop(self.0)
-        }
+    /// Pushes `field` of the table at `stack`, see [`protected_getfield`].
+    pub(crate) fn get_field(&self, field: &str, stack: i32) -> Result<i32, LunarError> {
+        protected_getfield(self.0, stack, field)?;
+        Ok(self.stack_size())
     }
 
     #[inline]
     pub fn get_type(&self, arg: i32) -> LuaType {
         unsafe {
-            return lua_type(self.0, arg).into();
+            lua_type(self.0, arg).into()
         }
     }
 
     #[inline]
     pub fn stack_size(&self) -> i32 {
         unsafe {
-            return lua_gettop(self.0);
+            lua_gettop(self.0)
         }
     }
 
-    pub fn call_function(&self, stack: i32, args: Vec<Value>, nresult: i32) {
+    pub fn call_function(&self, stack: i32, args: Vec<Value>, nresult: i32) -> Result<(), LunarError> {
         let nargs = args.len() as i32;
         unsafe {
             lua_pushvalue(self.L(), stack);
-            for v in args {
-                self.push(v);
-            }
-            pcall(self.L(), nargs, nresult, 0).unwrap();
         }
+        for v in args {
+            self.push(v);
+        }
+        pcall(self.L(), nargs, nresult)
     }
 
     pub fn stackdump(&self) {
@@ -218,17 +434,15 @@ impl LunarContext {
         for stack in 1..STACK_MAX {
             let typename = self.get_type(stack);
 
-            if (typename == LuaType::Table || typename == LuaType::Userdata)
-                && self.is_string("__name", stack)
-            {
-                let field = self.get_field("__name", stack);
-                let name = self.get_string(field).unwrap();
+            let name = match typename {
+                LuaType::Table | LuaType::Userdata => self.string_field("__name", stack),
+                _ => None,
+            };
+            if let Some(name) = name {
                 println!(
                     "Stack [{}]  <->  Type [{:?}]  <->  Name[{}]",
                     stack, typename, name
                 );
-
-                self.pop_last();
             } else {
                 println!("Stack [{}]  <->  Type [{:?}]", stack, typename);
             }
@@ -242,19 +456,23 @@ impl LunarContext {
     }
 
     pub(crate) fn set_metatable(&self, table: &Table, metatable: &MetaTable) {
-        let table = table.push_table();
-        metatable.push_metatable();
+        assert_usable(self.0);
+        let table = table.push_table(self.0);
+        metatable.push_metatable(self.0);
         unsafe {
             lua_setmetatable(self.0, table);
         }
         self.pop_last();
     }
 
-    fn is_string(&self, field: &str, stack: i32) -> bool {
-        self.get_field(field, stack);
-        let isstr = self.get_type(self.stack_size()) == LuaType::String;
+    fn string_field(&self, field: &str, stack: i32) -> Option<String> {
+        let field = self.get_field(field, stack).ok()?;
+        let string = match self.get_type(field) {
+            LuaType::String => self.get_string(field).ok(),
+            _ => None,
+        };
         self.pop_last();
-        return isstr;
+        string
     }
 
     fn push_userdata(&self, name: &str, ptr: *mut c_void, size: usize) {
@@ -263,7 +481,7 @@ impl LunarContext {
             let userdata = lua_gettop(self.0);
 
             if !name.is_empty() {
-                lua_getglobal(self.0, to_const_char(name.to_string()));
+                push_global(self.0, name);
                 lua_setmetatable(self.0, userdata);
             }
         }
@@ -275,7 +493,7 @@ impl LunarContext {
             let lightuserdata = lua_gettop(self.0);
 
             if !name.is_empty() {
-                lua_getglobal(self.0, to_const_char(name.to_string()));
+                push_global(self.0, name);
                 lua_setmetatable(self.0, lightuserdata);
             }
         }
@@ -0,0 +1,459 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{BuildHasher, Hash},
+};
+
+use crate::{
+    context::{AnyUserdata, LunarContext, Thread, Value},
+    error::LunarError,
+    function::LuaFunction,
+    lua::*,
+    table::Table,
+    types::{LuaType, Number, NumberConversion},
+};
+
+/// A Rust value that can be pushed onto the Lua stack as a single Lua value.
+///
+/// Integers beyond what the Lua version can represent are pushed as floats.
+pub trait IntoLua {
+    fn push_into(self, ctx: &LunarContext);
+}
+
+/// A Rust value that can be read back from a single Lua value.
+///
+/// Conversions are strict: a string is not read as a number nor the other way
+/// round, and a float is read as an integer only if it has no fractional part.
+pub trait FromLua: Sized {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError>;
+}
+
+/// Any number of Lua values, as taken or returned by a function. Tuples push
+/// one value per element, and any [`IntoLua`] value pushes itself.
+pub trait IntoLuaMulti {
+    /// Pushes the values, returns how many were pushed.
+    fn push_into_multi(self, ctx: &LunarContext) -> i32;
+}
+
+/// Any number of Lua values read from consecutive stack slots, `first` being
+/// the slot of the first one. Tuples read one slot per element.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(ctx: &LunarContext, first: i32) -> Result<Self, LunarError>;
+}
+
+/// The error for a value at `idx` of the wrong type.
+pub(crate) fn mismatch(ctx: &LunarContext, idx: i32, expected: &str) -> LunarError {
+    let found = unsafe { std::ffi::CStr::from_ptr(lua_typename(ctx.L(), lua_type(ctx.L(), idx))) };
+    LunarError::Conversion {
+        expected: expected.to_string(),
+        found: found.to_string_lossy().into_owned(),
+        message: None,
+    }
+}
+
+fn invalid(expected: &str, found: &str, message: String) -> LunarError {
+    LunarError::Conversion {
+        expected: expected.to_string(),
+        found: found.to_string(),
+        message: Some(message),
+    }
+}
+
+fn check_type(ctx: &LunarContext, idx: i32, ty: LuaType, expected: &str) -> Result<(), LunarError> {
+    if ctx.get_type(idx) == ty {
+        Ok(())
+    } else {
+        Err(mismatch(ctx, idx, expected))
+    }
+}
+
+/// Reads the number at `idx` as an integer, following the [`NumberConversion`]
+/// of the state. A float is kept as is if it has an integral value, as it may be
+/// out of the range of `i64` but in the one of the type asked for.
+fn to_integral(ctx: &LunarContext, idx: i32) -> Result<Number, LunarError> {
+    check_type(ctx, idx, LuaType::Number, "integer")?;
+    let n = match to_number(ctx.L(), idx) {
+        Number::Integer(n) => return Ok(Number::Integer(n)),
+        Number::Float(n) => n,
+    };
+
+    let conversion = number_conversion(ctx.L());
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    if conversion == NumberConversion::Strict {
+        return Err(invalid("integer", "number", "number is not an integer".to_string()));
+    }
+    let n = match conversion {
+        NumberConversion::Truncating => n.trunc(),
+        _ => n,
+    };
+    if n.fract() == 0.0 {
+        Ok(Number::Float(n))
+    } else {
+        Err(invalid("integer", "number", "number has no integer representation".to_string()))
+    }
+}
+
+fn out_of_range(ty: &str) -> LunarError {
+    invalid("integer", "number", format!("value out of range for {ty}"))
+}
+
+/// Reads the number at `idx` as a float, following the [`NumberConversion`] of the state.
+pub(crate) fn to_float(ctx: &LunarContext, idx: i32) -> Result<f64, LunarError> {
+    check_type(ctx, idx, LuaType::Number, "number")?;
+    let n = match to_number(ctx.L(), idx) {
+        Number::Float(n) => return Ok(n),
+        Number::Integer(n) => n,
+    };
+
+    match number_conversion(ctx.L()) {
+        NumberConversion::Strict => Err(invalid("number", "number", "number is not a float".to_string())),
+        NumberConversion::Lossless if n as f64 as i128 != n as i128 => Err(invalid(
+            "number",
+            "number",
+            "integer has no exact float representation".to_string(),
+        )),
+        _ => Ok(n as f64),
+    }
+}
+
+macro_rules! integer {
+    ($($ty:ty)*) => {$(
+        impl IntoLua for $ty {
+            fn push_into(self, ctx: &LunarContext) {
+                match i64::try_from(self) {
+                    Ok(n) => push_long(ctx.L(), n),
+                    Err(_) => unsafe { lua_pushnumber(ctx.L(), self as lua_Number) },
+                }
+            }
+        }
+
+        impl FromLua for $ty {
+            fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+                match to_integral(ctx, idx)? {
+                    Number::Integer(n) => <$ty>::try_from(n).map_err(|_| out_of_range(stringify!($ty))),
+                    // `MAX as f64` may round up to `MAX + 1`, which is then out of range.
+                    Number::Float(n) if n >= <$ty>::MIN as f64 && n < <$ty>::MAX as f64 + 1.0 => Ok(n as $ty),
+                    Number::Float(_) => Err(out_of_range(stringify!($ty))),
+                }
+            }
+        }
+    )*};
+}
+
+// Beyond the range of `i64`, `i128` and `u128` go through floats, so they keep
+// only the 53 bits of precision of a float there.
+integer!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize i128 u128);
+
+impl IntoLua for f64 {
+    fn push_into(self, ctx: &LunarContext) {
+        unsafe { lua_pushnumber(ctx.L(), self) }
+    }
+}
+
+impl FromLua for f64 {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        to_float(ctx, idx)
+    }
+}
+
+impl IntoLua for f32 {
+    fn push_into(self, ctx: &LunarContext) {
+        unsafe { lua_pushnumber(ctx.L(), self.into()) }
+    }
+}
+
+/// Infinities and NaN are kept, finite numbers beyond the range of `f32` are an error.
+impl FromLua for f32 {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        match to_float(ctx, idx)? {
+            n if n.is_finite() && n.abs() > f32::MAX.into() => {
+                Err(invalid("number", "number", "value out of range for f32".to_string()))
+            }
+            n => Ok(n as f32),
+        }
+    }
+}
+
+impl IntoLua for Number {
+    fn push_into(self, ctx: &LunarContext) {
+        match self {
+            Number::Integer(n) => push_long(ctx.L(), n),
+            Number::Float(n) => unsafe { lua_pushnumber(ctx.L(), n) },
+        }
+    }
+}
+
+/// Keeps the subtype of the number, whatever the [`NumberConversion`].
+impl FromLua for Number {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        check_type(ctx, idx, LuaType::Number, "number")?;
+        Ok(to_number(ctx.L(), idx))
+    }
+}
+
+impl IntoLua for bool {
+    fn push_into(self, ctx: &LunarContext) {
+        unsafe { lua_pushboolean(ctx.L(), self as i32) }
+    }
+}
+
+impl FromLua for bool {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        check_type(ctx, idx, LuaType::Bool, "boolean")?;
+        Ok(unsafe { lua_toboolean(ctx.L(), idx) } != 0)
+    }
+}
+
+impl IntoLua for &str {
+    fn push_into(self, ctx: &LunarContext) {
+        unsafe { lua_pushlstring(ctx.L(), self.as_ptr() as const_char, self.len()) }
+    }
+}
+
+impl IntoLua for String {
+    fn push_into(self, ctx: &LunarContext) {
+        self.as_str().push_into(ctx)
+    }
+}
+
+impl FromLua for String {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        check_type(ctx, idx, LuaType::String, "string")?;
+        let bytes = unsafe {
+            let mut len = 0;
+            let ptr = lua_tolstring(ctx.L(), idx, &mut len);
+            std::slice::from_raw_parts(ptr as *const u8, len)
+        };
+        Ok(std::str::from_utf8(bytes)?.to_string())
+    }
+}
+
+/// `None` is `nil`.
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn push_into(self, ctx: &LunarContext) {
+        match self {
+            Some(value) => value.push_into(ctx),
+            None => unsafe { lua_pushnil(ctx.L()) },
+        }
+    }
+}
+
+/// `nil`, or no value at all, is `None`.
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        match unsafe { lua_type(ctx.L(), idx) } {
+            LUA_TNIL | LUA_TNONE => Ok(None),
+            _ => T::from_lua(ctx, idx).map(Some),
+        }
+    }
+}
+
+/// A sequence, starting at index 1.
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn push_into(self, ctx: &LunarContext) {
+        unsafe {
+            lua_createtable(ctx.L(), self.len().try_into().unwrap_or(0), 0);
+            for (i, value) in self.into_iter().enumerate() {
+                value.push_into(ctx);
+                lua_rawseti(ctx.L(), -2, (i + 1) as lua_Integer);
+            }
+        }
+    }
+}
+
+/// The sequence part of a table, from index 1 up to its length. Metamethods
+/// are not called.
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        check_type(ctx, idx, LuaType::Table, "table")?;
+        let idx = absolute(ctx.L(), idx);
+        let len = unsafe { lua_rawlen(ctx.L(), idx) };
+
+        let mut values = Vec::with_capacity(len);
+        for i in 1..=len {
+            unsafe { lua_rawgeti(ctx.L(), idx, i as lua_Integer) };
+            let value = T::from_lua(ctx, -1);
+            ctx.pop_last();
+            values.push(value.map_err(|e| invalid("table", "table", format!("index {i}: {e}")))?);
+        }
+        Ok(values)
+    }
+}
+
+fn push_map<K: IntoLua, V: IntoLua>(ctx: &LunarContext, len: usize, map: impl Iterator<Item = (K, V)>) {
+    unsafe {
+        lua_createtable(ctx.L(), 0, len.try_into().unwrap_or(0));
+        for (key, value) in map {
+            key.push_into(ctx);
+            value.push_into(ctx);
+            lua_rawset(ctx.L(), -3);
+        }
+    }
+}
+
+/// Calls `f` with each key and value of the table at `idx`, without calling metamethods.
+fn for_each_pair<K: FromLua, V: FromLua>(
+    ctx: &LunarContext,
+    idx: i32,
+    mut f: impl FnMut(K, V),
+) -> Result<(), LunarError> {
+    check_type(ctx, idx, LuaType::Table, "table")?;
+    let idx = absolute(ctx.L(), idx);
+
+    unsafe { lua_pushnil(ctx.L()) };
+    while unsafe { lua_next(ctx.L(), idx) } != 0 {
+        let pair = K::from_lua(ctx, -2).and_then(|key| Ok((key, V::from_lua(ctx, -1)?)));
+        ctx.pop_last();
+        match pair {
+            Ok((key, value)) => f(key, value),
+            Err(e) => {
+                ctx.pop_last();
+                return Err(invalid("table", "table", format!("invalid pair: {e}")));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<K: IntoLua, V: IntoLua, S> IntoLua for HashMap<K, V, S> {
+    fn push_into(self, ctx: &LunarContext) {
+        push_map(ctx, self.len(), self.into_iter())
+    }
+}
+
+impl<K, V, S> FromLua for HashMap<K, V, S>
+where
+    K: FromLua + Eq + Hash,
+    V: FromLua,
+    S: BuildHasher + Default,
+{
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        let mut map = HashMap::default();
+        for_each_pair(ctx, idx, |key, value| {
+            map.insert(key, value);
+        })?;
+        Ok(map)
+    }
+}
+
+impl<K: IntoLua, V: IntoLua> IntoLua for BTreeMap<K, V> {
+    fn push_into(self, ctx: &LunarContext) {
+        push_map(ctx, self.len(), self.into_iter())
+    }
+}
+
+impl<K: FromLua + Ord, V: FromLua> FromLua for BTreeMap<K, V> {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        let mut map = BTreeMap::new();
+        for_each_pair(ctx, idx, |key, value| {
+            map.insert(key, value);
+        })?;
+        Ok(map)
+    }
+}
+
+impl IntoLua for Table {
+    fn push_into(self, ctx: &LunarContext) {
+        (&self).push_into(ctx)
+    }
+}
+
+impl IntoLua for &Table {
+    fn push_into(self, ctx: &LunarContext) {
+        self.push_table(ctx.L());
+    }
+}
+
+/// A handle on the same table, not a copy.
+impl FromLua for Table {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        check_type(ctx, idx, LuaType::Table, "table")?;
+        Ok(Table::from_stack(ctx, idx))
+    }
+}
+
+macro_rules! handle {
+    ($($ty:ident $variant:ident $lua:ident $name:literal)*) => {$(
+        impl IntoLua for $ty {
+            fn push_into(self, ctx: &LunarContext) {
+                ctx.push(Value::$variant(self))
+            }
+        }
+
+        impl FromLua for $ty {
+            fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+                check_type(ctx, idx, LuaType::$lua, $name)?;
+                match ctx.get_value(idx) {
+                    Value::$variant(handle) => Ok(handle),
+                    _ => Err(mismatch(ctx, idx, $name)),
+                }
+            }
+        }
+    )*};
+}
+
+handle! {
+    LuaFunction LuaFunction Function "function"
+    AnyUserdata AnyUserdata Userdata "userdata"
+    Thread Thread Thread "thread"
+}
+
+impl IntoLua for Value {
+    fn push_into(self, ctx: &LunarContext) {
+        ctx.push(self)
+    }
+}
+
+impl FromLua for Value {
+    fn from_lua(ctx: &LunarContext, idx: i32) -> Result<Self, LunarError> {
+        Ok(ctx.get_value(idx))
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn push_into_multi(self, ctx: &LunarContext) -> i32 {
+        self.push_into(ctx);
+        1
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(ctx: &LunarContext, first: i32) -> Result<Self, LunarError> {
+        T::from_lua(ctx, first)
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident)*) => {
+        impl<$($name: IntoLua),*> IntoLuaMulti for ($($name,)*) {
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn push_into_multi(self, ctx: &LunarContext) -> i32 {
+                let ($($name,)*) = self;
+                let mut count = 0;
+                $($name.push_into(ctx); count += 1;)*
+                count
+            }
+        }
+
+        impl<$($name: FromLua),*> FromLuaMulti for ($($name,)*) {
+            #[allow(unused_variables, unused_mut, unused_assignments)]
+            fn from_lua_multi(ctx: &LunarContext, first: i32) -> Result<Self, LunarError> {
+                let mut idx = absolute(ctx.L(), first);
+                Ok(($({
+                    let value = $name::from_lua(ctx, idx)?;
+                    idx += 1;
+                    value
+                },)*))
+            }
+        }
+    };
+}
+
+tuple!();
+tuple!(A);
+tuple!(A B);
+tuple!(A B C);
+tuple!(A B C D);
+tuple!(A B C D E);
+tuple!(A B C D E F);
+tuple!(A B C D E F G);
+tuple!(A B C D E F G H);
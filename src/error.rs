@@ -0,0 +1,447 @@
+use std::{error::Error, fmt, str::Utf8Error, sync::Arc};
+#[cfg(feature = "dynamic")]
+use std::path::PathBuf;
+
+use crate::{context::Value, types::Number};
+
+/// Errors reported by Lunar.
+///
+/// Errors coming from Lua carry the chunk name and line they were raised at,
+/// when Lua reported one, and the message without that location prefix. Errors
+/// raised while running code also carry the call stack, see [`LunarError::traceback`].
+///
+/// A [`LunarError::Value`] holds the raised value itself, tied to the state, so
+/// errors are neither `Send` nor `Sync`. Its `snapshot` is a copy that is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LunarError {
+    /// A chunk failed to compile.
+    Syntax {
+        chunk: Option<String>,
+        line: Option<u32>,
+        message: String,
+    },
+    /// An error raised while running Lua code.
+    Runtime {
+        chunk: Option<String>,
+        line: Option<u32>,
+        message: String,
+        traceback: Box<[Frame]>,
+    },
+    /// Lua failed to allocate memory.
+    Memory(String),
+    /// The message handler of a protected call failed itself.
+    MessageHandler(String),
+    /// A function was called with an argument of the wrong type.
+    TypeMismatch {
+        chunk: Option<String>,
+        line: Option<u32>,
+        /// Position of the argument, starting at 1.
+        position: i32,
+        /// Name of the called function, when Lua could tell.
+        function: Option<String>,
+        expected: String,
+        found: String,
+        traceback: Box<[Frame]>,
+    },
+    /// A Lua string was not valid UTF-8.
+    Utf8(Utf8Error),
+    /// A Lua value could not be converted to the Rust type asked for, see
+    /// [`FromLua`](crate::convert::FromLua).
+    Conversion {
+        expected: String,
+        found: String,
+        /// Why the conversion failed, when the value had the right type.
+        message: Option<String>,
+    },
+    /// An error raised by a Rust callback with [`LunarContext::error`](crate::context::LunarContext::error).
+    Callback {
+        chunk: Option<String>,
+        line: Option<u32>,
+        message: String,
+        traceback: Box<[Frame]>,
+    },
+    /// A Rust callback panicked. The panic was stopped at the callback and
+    /// travelled through Lua as an error, so `pcall` can catch it too.
+    CallbackPanic {
+        chunk: Option<String>,
+        line: Option<u32>,
+        message: String,
+        traceback: Box<[Frame]>,
+    },
+    /// A value other than a string or a number raised from Lua, as in
+    /// `error({code = 404})`. Tables stay reachable through their [`Table`](crate::table::Table)
+    /// handle. The message comes from the `__tostring` metamethod of the value, if any.
+    Value {
+        value: Value,
+        /// Copy of `value` that outlives the state.
+        snapshot: ErrorValue,
+        message: String,
+        traceback: Box<[Frame]>,
+    },
+    /// An error returned by a fallible Rust callback, see [`LunarError::external`].
+    External(ExternalError),
+    /// The Lua shared library could not be loaded.
+    #[cfg(feature = "dynamic")]
+    Library(LibraryError),
+}
+
+impl LunarError {
+    /// Classifies an error message left on the stack by Lua, `status` being the
+    /// code returned by the failed call.
+    pub(crate) fn from_lua(status: i32, message: String) -> LunarError {
+        use crate::lua::{LUA_ERRERR, LUA_ERRMEM, LUA_ERRSYNTAX};
+
+        match status {
+            LUA_ERRMEM => return LunarError::Memory(message),
+            LUA_ERRERR => return LunarError::MessageHandler(message),
+            _ => {}
+        }
+
+        let (chunk, line, text) = split_location(&message);
+        if status == LUA_ERRSYNTAX {
+            return LunarError::Syntax {
+                chunk,
+                line,
+                message: text.to_string(),
+            };
+        }
+
+        match parse_type_mismatch(text) {
+            Some((position, function, expected, found)) => LunarError::TypeMismatch {
+                chunk,
+                line,
+                position,
+                function,
+                expected,
+                found,
+                traceback: Box::default(),
+            },
+            None => LunarError::Runtime {
+                chunk,
+                line,
+                message: text.to_string(),
+                traceback: Box::default(),
+            },
+        }
+    }
+
+    /// Wraps any error, so that a callback can return it, e.g. with
+    /// `.map_err(LunarError::external)?`.
+    pub fn external<E: Error + Send + Sync + 'static>(error: E) -> LunarError {
+        LunarError::External(ExternalError(Arc::new(error)))
+    }
+
+    /// The error wrapped by [`LunarError::external`], if it is a `T`.
+    pub fn downcast_ref<T: Error + 'static>(&self) -> Option<&T> {
+        match self {
+            LunarError::External(ExternalError(error)) => error.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Chunk name the error was raised in, if known.
+    pub fn chunk(&self) -> Option<&str> {
+        match self {
+            LunarError::Syntax { chunk, .. }
+            | LunarError::Runtime { chunk, .. }
+            | LunarError::TypeMismatch { chunk, .. }
+            | LunarError::Callback { chunk, .. }
+            | LunarError::CallbackPanic { chunk, .. } => chunk.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Call stack at the time of the error, innermost frame first.
+    ///
+    /// Empty for errors that did not happen while running code, or when the
+    /// message handler was replaced with [`Lunar::set_message_handler`](crate::lunar::Lunar::set_message_handler).
+    pub fn traceback(&self) -> &[Frame] {
+        match self {
+            LunarError::Runtime { traceback, .. }
+            | LunarError::TypeMismatch { traceback, .. }
+            | LunarError::Callback { traceback, .. }
+            | LunarError::CallbackPanic { traceback, .. }
+            | LunarError::Value { traceback, .. } => traceback,
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn set_traceback(&mut self, frames: Vec<Frame>) {
+        match self {
+            LunarError::Runtime { traceback, .. }
+            | LunarError::TypeMismatch { traceback, .. }
+            | LunarError::Callback { traceback, .. }
+            | LunarError::CallbackPanic { traceback, .. }
+            | LunarError::Value { traceback, .. } => *traceback = frames.into(),
+            _ => {}
+        }
+    }
+
+    /// Line the error was raised at, if known.
+    pub fn line(&self) -> Option<u32> {
+        match self {
+            LunarError::Syntax { line, .. }
+            | LunarError::Runtime { line, .. }
+            | LunarError::TypeMismatch { line, .. }
+            | LunarError::Callback { line, .. }
+            | LunarError::CallbackPanic { line, .. } => *line,
+            _ => None,
+        }
+    }
+}
+
+/// Shows the traceback too with the alternate flag, `{:#}`.
+impl fmt::Display for LunarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(chunk), Some(line)) = (self.chunk(), self.line()) {
+            write!(f, "{chunk}:{line}: ")?;
+        }
+        self.fmt_message(f)?;
+
+        if f.alternate() && !self.traceback().is_empty() {
+            write!(f, "\nstack traceback:")?;
+            for frame in self.traceback() {
+                write!(f, "\n\t{frame}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LunarError {
+    fn fmt_message(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LunarError::Syntax { message, .. }
+            | LunarError::Runtime { message, .. }
+            | LunarError::Callback { message, .. }
+            | LunarError::Value { message, .. } => write!(f, "{message}"),
+            LunarError::CallbackPanic { message, .. } => write!(f, "callback panicked: {message}"),
+            LunarError::Conversion { message: Some(message), .. } => write!(f, "{message}"),
+            LunarError::Conversion { expected, found, .. } => write!(f, "{expected} expected, got {found}"),
+            LunarError::Memory(message) => write!(f, "memory error: {message}"),
+            LunarError::MessageHandler(message) => write!(f, "error in message handler: {message}"),
+            LunarError::TypeMismatch {
+                position,
+                function,
+                expected,
+                found,
+                ..
+            } => match function {
+                Some(function) => write!(
+                    f,
+                    "bad argument #{position} to '{function}' ({expected} expected, got {found})"
+                ),
+                None => write!(f, "bad argument #{position} ({expected} expected, got {found})"),
+            },
+            LunarError::Utf8(e) => write!(f, "invalid UTF-8 string: {e}"),
+            LunarError::External(e) => write!(f, "{e}"),
+            #[cfg(feature = "dynamic")]
+            LunarError::Library(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Copy of a Lua value raised as an error, see [`LunarError::Value`], independent
+/// of the state it came from.
+///
+/// Tables are copied with their contents, read raw and up to a few levels deep.
+/// Other objects keep their type name only.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorValue {
+    Nil,
+    Bool(bool),
+    Number(Number),
+    /// A string, invalid UTF-8 replaced.
+    String(String),
+    Table(Vec<(ErrorValue, ErrorValue)>),
+    /// A function, a userdata, a thread or a table nested too deep, by type name.
+    Other(String),
+}
+
+impl ErrorValue {
+    /// Field `key` of a table, `None` for other values.
+    pub fn get(&self, key: &str) -> Option<&ErrorValue> {
+        match self {
+            ErrorValue::Table(pairs) => pairs
+                .iter()
+                .find(|(k, _)| matches!(k, ErrorValue::String(k) if k == key))
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+/// Error of a Rust callback, shared so that [`LunarError`] stays `Clone`. Two are
+/// equal only if they are the same error.
+#[derive(Clone)]
+pub struct ExternalError(Arc<dyn Error + Send + Sync>);
+
+impl PartialEq for ExternalError {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for ExternalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for ExternalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// A function on the call stack of an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Chunk the function was defined in, `[C]` for native functions.
+    pub source: String,
+    /// Line being run, unknown for native functions.
+    pub line: Option<u32>,
+    /// Name the function was called by, when Lua could tell.
+    pub name: Option<String>,
+    pub kind: FrameKind,
+}
+
+/// What kind of function a [`Frame`] is running.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    /// A Lua function or chunk.
+    Lua,
+    /// A function registered through Lunar.
+    Rust,
+    /// Any other native function, like those of the standard library.
+    C,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        match &self.name {
+            Some(name) => write!(f, ": in function '{name}'"),
+            None => write!(f, ": in ?"),
+        }
+    }
+}
+
+impl Error for LunarError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LunarError::Utf8(e) => Some(e),
+            LunarError::External(ExternalError(e)) => Some(e.as_ref()),
+            #[cfg(feature = "dynamic")]
+            LunarError::Library(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Utf8Error> for LunarError {
+    fn from(value: Utf8Error) -> Self {
+        LunarError::Utf8(value)
+    }
+}
+
+#[cfg(feature = "dynamic")]
+impl From<LibraryError> for LunarError {
+    fn from(value: LibraryError) -> Self {
+        LunarError::Library(value)
+    }
+}
+
+/// Splits the `chunk:line:` prefix Lua adds to error messages.
+///
+/// Chunks loaded from strings are named `[string "..."]`, and the source in
+/// there may contain anything, colons included.
+pub(crate) fn split_location(message: &str) -> (Option<String>, Option<u32>, &str) {
+    let chunk_end = if message.starts_with("[string \"") {
+        message.find("\"]:").map(|i| i + 2)
+    } else {
+        message.char_indices().filter(|&(_, c)| c == ':').map(|(i, _)| i).find(|&i| {
+            let rest = &message[i + 1..];
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            digits > 0 && rest[digits..].starts_with(':')
+        })
+    };
+
+    let Some(chunk_end) = chunk_end else {
+        return (None, None, message);
+    };
+
+    let rest = &message[chunk_end + 1..];
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    match (rest[..digits].parse(), rest[digits..].strip_prefix(':')) {
+        (Ok(line), Some(text)) => (
+            Some(message[..chunk_end].to_string()),
+            Some(line),
+            text.trim_start(),
+        ),
+        _ => (None, None, message),
+    }
+}
+
+/// Parses `bad argument #1 to 'name' (number expected, got string)`, which Luau
+/// words as `invalid argument #1 ...`.
+fn parse_type_mismatch(text: &str) -> Option<(i32, Option<String>, String, String)> {
+    let rest = text
+        .strip_prefix("bad argument #")
+        .or_else(|| text.strip_prefix("invalid argument #"))?;
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    let position = rest[..digits].parse().ok()?;
+    let rest = &rest[digits..];
+
+    let (function, rest) = match rest.strip_prefix(" to '") {
+        Some(rest) => {
+            let end = rest.find('\'')?;
+            (Some(rest[..end].to_string()), &rest[end + 1..])
+        }
+        None => (None, rest),
+    };
+
+    let detail = rest.trim_start().strip_prefix('(')?.strip_suffix(')')?;
+    let (expected, found) = detail.split_once(" expected, got ")?;
+    Some((position, function, expected.to_string(), found.to_string()))
+}
+
+/// Failure to load a Lua shared library with [`Lunar::with_library`](crate::lunar::Lunar::with_library).
+#[cfg(feature = "dynamic")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LibraryError {
+    /// The library could not be opened.
+    Load { path: PathBuf, message: String },
+    /// The library does not export a function of the Lua API.
+    MissingSymbol(&'static str),
+    /// Another library was already loaded, only one can be used per process.
+    AlreadyLoaded(PathBuf),
+    /// A state was created before any library was loaded.
+    NotLoaded,
+}
+
+#[cfg(feature = "dynamic")]
+impl fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LibraryError::Load { path, message } => {
+                write!(f, "unable to load '{}': {message}", path.display())
+            }
+            LibraryError::MissingSymbol(symbol) => {
+                write!(f, "symbol '{symbol}' not found in the Lua library")
+            }
+            LibraryError::AlreadyLoaded(path) => {
+                write!(f, "a Lua library is already loaded from '{}'", path.display())
+            }
+            LibraryError::NotLoaded => {
+                write!(f, "no Lua library loaded, create the first state with 'Lunar::with_library'")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "dynamic")]
+impl Error for LibraryError {}
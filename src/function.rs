@@ -0,0 +1,144 @@
+#![allow(non_snake_case)]
+
+use crate::{
+    context::LunarContext,
+    convert::{FromLua, FromLuaMulti, IntoLuaMulti},
+    error::LunarError,
+    lua::*,
+    refr::LuaRef,
+};
+
+/// A handle on a Lua function, or on a Rust callback pushed to Lua, kept alive
+/// through the registry.
+///
+/// Obtained by reading a function from a global, a table field or a callback
+/// argument, or from [`Lunar::load_function`](crate::lunar::Lunar::load_function),
+/// and called from Rust with [`call`](LuaFunction::call).
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaFunction {
+    luaref: LuaRef,
+}
+
+impl LuaFunction {
+    /// Handle on the function at `idx`.
+    pub(crate) fn from_stack(ctx: &LunarContext, idx: i32) -> LuaFunction {
+        unsafe { lua_pushvalue(ctx.L(), idx) };
+        LuaFunction {
+            luaref: LuaRef::register_last_stack_value(ctx.L()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn push_function(&self, L: lua_State) -> i32 {
+        self.luaref.push_onto(L)
+    }
+
+    /// Calls the function in protected mode, with the message handler of
+    /// [`exec`](crate::lunar::Lunar::exec). Results are read as an `R`, e.g. a
+    /// tuple for several of them, then removed from the stack.
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(&self, args: A) -> Result<R, LunarError> {
+        usable(self.luaref.L())?;
+        let ctx = LunarContext::new(self.luaref.L());
+        self.luaref.push_reference();
+        call_pushed(&ctx, args)
+    }
+}
+
+/// Calls the function on top of the stack with `args` and reads its results,
+/// leaving the stack as it was below the function.
+pub(crate) fn call_pushed<A: IntoLuaMulti, R: FromLuaMulti>(ctx: &LunarContext, args: A) -> Result<R, LunarError> {
+    let base = ctx.stack_size() - 1;
+    let nargs = args.push_into_multi(ctx);
+    pcall(ctx.L(), nargs, LUA_MULTRET)?;
+
+    let results = R::from_lua_multi(ctx, base + 1);
+    unsafe { lua_settop(ctx.L(), base) };
+    results
+}
+
+/// A Rust function whose arguments and results convert to and from Lua, see
+/// [`Lunar::register`](crate::lunar::Lunar::register). Implemented for closures
+/// of up to 8 arguments implementing [`FromLua`], returning an [`IntoLuaMulti`]
+/// or a `Result` of one.
+pub trait TypedFunction<Args>: 'static {
+    /// Reads the arguments from `ctx`, calls the function and pushes its results.
+    /// `name` is the name errors report the function by.
+    fn call_typed(&self, ctx: &LunarContext, name: &str) -> Result<i32, LunarError>;
+}
+
+/// What a [`TypedFunction`] may return: values to push, or a `Result` of them
+/// whose error is raised in Lua.
+pub trait TypedResult {
+    fn push_results(self, ctx: &LunarContext) -> Result<i32, LunarError>;
+}
+
+impl<T: IntoLuaMulti> TypedResult for T {
+    fn push_results(self, ctx: &LunarContext) -> Result<i32, LunarError> {
+        Ok(self.push_into_multi(ctx))
+    }
+}
+
+impl<T: IntoLuaMulti, E: Into<LunarError>> TypedResult for Result<T, E> {
+    fn push_results(self, ctx: &LunarContext) -> Result<i32, LunarError> {
+        self.map(|values| values.push_into_multi(ctx)).map_err(Into::into)
+    }
+}
+
+/// Reads argument `position` of the function `name`. A value of the wrong type
+/// is reported the way Lua reports it, naming the function.
+fn argument<T: FromLua>(ctx: &LunarContext, name: &str, position: i32) -> Result<T, LunarError> {
+    T::from_lua(ctx, position).map_err(|error| match error {
+        LunarError::Conversion { expected, found, message: None } => {
+            let (chunk, line) = location(ctx.L(), 1);
+            LunarError::TypeMismatch {
+                chunk,
+                line,
+                position,
+                function: Some(name.to_string()),
+                expected,
+                found,
+                traceback: Box::default(),
+            }
+        }
+        error => callback_error(ctx.L(), format!("bad argument #{position} to '{name}' ({error})")),
+    })
+}
+
+macro_rules! typed_function {
+    ($($name:ident)*) => {
+        impl<Func, Ret, $($name: FromLua),*> TypedFunction<($($name,)*)> for Func
+        where
+            Func: Fn($($name),*) -> Ret + 'static,
+            Ret: TypedResult,
+        {
+            #[allow(unused_variables, unused_mut, unused_assignments)]
+            fn call_typed(&self, ctx: &LunarContext, name: &str) -> Result<i32, LunarError> {
+                let arity = <[&str]>::len(&[$(stringify!($name)),*]) as i32;
+                let given = ctx.stack_size();
+                if given > arity {
+                    return Err(callback_error(
+                        ctx.L(),
+                        format!("wrong number of arguments to '{name}' ({arity} expected, got {given})"),
+                    ));
+                }
+
+                let mut position = 0;
+                let result = self($({
+                    position += 1;
+                    argument::<$name>(ctx, name, position)?
+                }),*);
+                result.push_results(ctx)
+            }
+        }
+    };
+}
+
+typed_function!();
+typed_function!(A);
+typed_function!(A B);
+typed_function!(A B C);
+typed_function!(A B C D);
+typed_function!(A B C D E);
+typed_function!(A B C D E F);
+typed_function!(A B C D E F G);
+typed_function!(A B C D E F G H);
@@ -1,4 +1,8 @@
+pub mod args;
 pub mod context;
+pub mod convert;
+pub mod error;
+pub mod function;
 pub mod lunar;
 pub mod metatable;
 pub mod table;
@@ -3,98 +3,691 @@
 #![allow(dead_code)]
 
 use libc::*;
-use std::ffi::{CStr, CString};
+use std::{
+    ffi::{CStr, CString},
+    panic,
+};
+#[cfg(feature = "dynamic")]
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::{
+    context::{CallbackResult, LunarContext},
+    error::{split_location, ErrorValue, Frame, FrameKind, LunarError},
+    types::{Number, NumberConversion},
+};
+#[cfg(feature = "dynamic")]
+use crate::error::LibraryError;
 
 pub type void_ptr = *mut c_void;
 pub type lua_State = void_ptr;
 pub type lua_CFunction = void_ptr;
 pub type const_char = *mut c_char;
 pub type lua_Value = void_ptr;
+#[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+pub type lua_Integer = isize;
+#[cfg(any(feature = "lua53", feature = "lua54"))]
 pub type lua_Integer = i64;
+#[cfg(feature = "luau")]
+pub type lua_Integer = i32;
 pub type lua_Unsigned = u64;
 pub type lua_Number = f64;
-pub type LunarError = &'static str;
-pub type lua_Writer = extern "C" fn(L: lua_State, void: void_ptr, __size: usize, ud: void_ptr);
+pub type lua_KContext = isize;
+pub type lua_Destructor = unsafe extern "C" fn(ud: void_ptr);
+pub type lua_Writer =
+    extern "C" fn(L: lua_State, p: *const c_void, size: usize, ud: void_ptr) -> i32;
+
+#[cfg(not(feature = "luau"))]
+pub const LUA_IDSIZE: usize = 60;
+#[cfg(feature = "luau")]
+pub const LUA_IDSIZE: usize = 256;
+
+#[cfg(any(feature = "lua51", feature = "luajit"))]
+#[repr(C)]
+pub struct lua_Debug {
+    pub event: i32,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub currentline: i32,
+    pub nups: i32,
+    pub linedefined: i32,
+    pub lastlinedefined: i32,
+    pub short_src: [c_char; LUA_IDSIZE],
+    i_ci: i32,
+}
+
+#[cfg(any(feature = "lua52", feature = "lua53"))]
+#[repr(C)]
+pub struct lua_Debug {
+    pub event: i32,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub currentline: i32,
+    pub linedefined: i32,
+    pub lastlinedefined: i32,
+    pub nups: u8,
+    pub nparams: u8,
+    pub isvararg: c_char,
+    pub istailcall: c_char,
+    pub short_src: [c_char; LUA_IDSIZE],
+    i_ci: void_ptr,
+}
+
+#[cfg(feature = "lua54")]
+#[repr(C)]
+pub struct lua_Debug {
+    pub event: i32,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub srclen: usize,
+    pub currentline: i32,
+    pub linedefined: i32,
+    pub lastlinedefined: i32,
+    pub nups: u8,
+    pub nparams: u8,
+    pub isvararg: c_char,
+    pub istailcall: c_char,
+    pub ftransfer: u16,
+    pub ntransfer: u16,
+    pub short_src: [c_char; LUA_IDSIZE],
+    i_ci: void_ptr,
+}
+
+#[cfg(feature = "luau")]
+#[repr(C)]
+pub struct lua_Debug {
+    pub name: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub short_src: *const c_char,
+    pub linedefined: i32,
+    pub currentline: i32,
+    pub nupvals: u8,
+    pub nparams: u8,
+    pub isvararg: c_char,
+    pub userdata: void_ptr,
+    pub ssbuf: [c_char; LUA_IDSIZE],
+}
+
+/// Only the start of Luau's `lua_Callbacks`, which is never allocated from Rust.
+#[cfg(feature = "luau")]
+#[repr(C)]
+pub struct lua_Callbacks {
+    pub userdata: void_ptr,
+    pub interrupt: void_ptr,
+    pub panic: Option<unsafe extern "C-unwind" fn(L: lua_State, errcode: i32)>,
+}
 
+#[cfg(any(feature = "lua51", feature = "luajit"))]
+pub const LUA_REGISTRYINDEX: i32 = -10000;
+#[cfg(any(feature = "lua51", feature = "luajit"))]
+pub const LUA_GLOBALSINDEX: i32 = -10002;
+
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
 pub const LUAI_MAXSTACK: i32 = 1000000;
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
 pub const LUA_REGISTRYINDEX: i32 = -LUAI_MAXSTACK - 1000;
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
+pub const LUA_RIDX_MAINTHREAD: lua_Integer = 1;
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
+pub const LUA_RIDX_GLOBALS: lua_Integer = 2;
+
+/// Must match the value the vendored Luau VM is built with, see `build.rs`.
+#[cfg(feature = "luau")]
+pub const LUAI_MAXCSTACK: i32 = 100000;
+#[cfg(feature = "luau")]
+pub const LUA_REGISTRYINDEX: i32 = -LUAI_MAXCSTACK - 2000;
+#[cfg(feature = "luau")]
+pub const LUA_GLOBALSINDEX: i32 = -LUAI_MAXCSTACK - 2002;
+
+pub const LUA_MULTRET: i32 = -1;
+pub const LUA_MINSTACK: i32 = 20;
+
+pub const LUA_TNONE: i32 = -1;
+pub const LUA_TNIL: i32 = 0;
+pub const LUA_TBOOLEAN: i32 = 1;
+pub const LUA_TLIGHTUSERDATA: i32 = 2;
+pub const LUA_TNUMBER: i32 = 3;
+#[cfg(not(feature = "luau"))]
+pub const LUA_TSTRING: i32 = 4;
+#[cfg(not(feature = "luau"))]
+pub const LUA_TTABLE: i32 = 5;
+#[cfg(not(feature = "luau"))]
+pub const LUA_TFUNCTION: i32 = 6;
+#[cfg(not(feature = "luau"))]
+pub const LUA_TUSERDATA: i32 = 7;
+#[cfg(not(feature = "luau"))]
+pub const LUA_TTHREAD: i32 = 8;
+#[cfg(feature = "luau")]
+pub const LUA_TVECTOR: i32 = 4;
+#[cfg(feature = "luau")]
+pub const LUA_TSTRING: i32 = 5;
+#[cfg(feature = "luau")]
+pub const LUA_TTABLE: i32 = 6;
+#[cfg(feature = "luau")]
+pub const LUA_TFUNCTION: i32 = 7;
+#[cfg(feature = "luau")]
+pub const LUA_TUSERDATA: i32 = 8;
+#[cfg(feature = "luau")]
+pub const LUA_TTHREAD: i32 = 9;
+#[cfg(feature = "luau")]
+pub const LUA_TBUFFER: i32 = 10;
+
+pub const LUA_OK: i32 = 0;
+pub const LUA_YIELD: i32 = 1;
+pub const LUA_ERRRUN: i32 = 2;
+pub const LUA_ERRSYNTAX: i32 = 3;
+pub const LUA_ERRMEM: i32 = 4;
+#[cfg(any(feature = "lua52", feature = "lua53"))]
+pub const LUA_ERRGCMM: i32 = 5;
+#[cfg(any(feature = "lua51", feature = "lua54", feature = "luajit", feature = "luau"))]
+pub const LUA_ERRERR: i32 = 5;
+#[cfg(any(feature = "lua52", feature = "lua53"))]
+pub const LUA_ERRERR: i32 = 6;
+
+#[cfg(feature = "luajit")]
+pub const LUAJIT_MODE_ENGINE: i32 = 0;
+#[cfg(feature = "luajit")]
+pub const LUAJIT_MODE_OFF: i32 = 0x0000;
+#[cfg(feature = "luajit")]
+pub const LUAJIT_MODE_ON: i32 = 0x0100;
+#[cfg(feature = "luajit")]
+pub const LUAJIT_MODE_FLUSH: i32 = 0x0200;
+
+/// Declares the Lua C API.
+///
+/// Without the `dynamic` feature this is a plain `extern "C-unwind"` block resolved
+/// by the linker. Most of the API may raise a Lua error, which LuaJIT and Luau
+/// builds propagate by unwinding through the Rust frames that called it.
+///
+/// With `dynamic`, every function becomes a thin wrapper around a pointer resolved
+/// from the library passed to [`load_library`], so a missing symbol is reported
+/// when the library is loaded instead of failing the link.
+macro_rules! lua_api {
+    ($(
+        $(#[cfg($cfg:meta)])*
+        $(#[link_name = $symbol:literal])?
+        $vis:vis fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;
+    )*) => {
+        #[cfg(not(feature = "dynamic"))]
+        #[cfg_attr(
+            all(windows, not(any(feature = "vendored", feature = "system-lua"))),
+            link(name = "lua", kind = "raw-dylib")
+        )]
+        extern "C-unwind" {
+            $(
+                $(#[cfg($cfg)])*
+                $(#[link_name = $symbol])?
+                $vis fn $name($($arg: $ty),*) $(-> $ret)?;
+            )*
+        }
+
+        #[cfg(feature = "dynamic")]
+        struct Api {
+            $(
+                $(#[cfg($cfg)])*
+                $name: unsafe extern "C-unwind" fn($($ty),*) $(-> $ret)?,
+            )*
+        }
+
+        #[cfg(feature = "dynamic")]
+        impl Api {
+            unsafe fn resolve(library: &libloading::Library) -> Result<Api, LibraryError> {
+                Ok(Api {
+                    $(
+                        $(#[cfg($cfg)])*
+                        $name: resolve(library, lua_api!(@symbol $name $($symbol)?))?,
+                    )*
+                })
+            }
+        }
+
+        $(
+            #[cfg(feature = "dynamic")]
+            $(#[cfg($cfg)])*
+            #[inline]
+            $vis unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                (api().$name)($($arg),*)
+            }
+        )*
+    };
+    (@symbol $name:ident) => { stringify!($name) };
+    (@symbol $name:ident $symbol:literal) => { $symbol };
+}
 
-#[link(name = "lua", kind = "raw-dylib")]
-extern "C" {
+lua_api! {
     pub fn luaL_newstate() -> lua_State;
     pub fn luaL_openlibs(L: lua_State);
     pub fn lua_close(L: lua_State);
-    pub fn luaL_loadstring(L: lua_State, value: const_char);
-    pub fn luaL_error(L: lua_State, fmt: const_char, ...) -> i32;
-    pub fn lua_pcallk(
-        L: lua_State,
-        nargs: i32,
-        nresults: i32,
-        errfunc: i32,
-        ctx: i32,
-        k: i32,
-    ) -> i32;
     pub fn lua_tolstring(L: lua_State, stack: i32, len: *mut size_t) -> const_char;
-    pub fn lua_tostring(L: lua_State, stack: i32);
     pub fn lua_type(L: lua_State, stack: i32) -> i32;
+    pub fn lua_typename(L: lua_State, tp: i32) -> const_char;
 
-    pub fn lua_setglobal(L: lua_State, key: const_char);
     pub fn lua_setfield(L: lua_State, stack: i32, key: const_char);
+    pub fn lua_settable(L: lua_State, stack: i32);
+    pub fn lua_gettable(L: lua_State, stack: i32);
 
-    pub fn luaL_checkinteger(L: lua_State, stack: i32) -> i64;
-    pub fn lua_toboolean(L: lua_State, stack: i32) -> bool;
-    pub fn luaL_checknumber(L: lua_State, stack: i32) -> f64;
-    pub fn luaL_checklstring(L: lua_State, stack: i32, len: *mut size_t) -> const_char;
+    pub fn lua_toboolean(L: lua_State, stack: i32) -> i32;
     pub fn lua_tocfunction(L: lua_State, stack: i32) -> lua_CFunction;
     pub fn lua_gettop(L: lua_State) -> i32;
-    pub fn lua_geti(L: lua_State, stack: i32, idx: lua_Integer) -> i32;
-    pub fn lua_getglobal(L: lua_State, name: const_char) -> i32;
-    pub fn lua_getfield(L: lua_State, stack: i32, key: const_char) -> i32;
 
     pub fn lua_touserdata(L: lua_State, idx: i32) -> *mut std::ffi::c_void;
-    pub fn luaL_ref(L: lua_State, t: i32) -> i32;
     pub fn lua_pushnil(L: lua_State);
     pub fn lua_pushnumber(L: lua_State, number: lua_Number);
     pub fn lua_pushinteger(L: lua_State, n: lua_Integer);
     pub fn lua_pushlstring(L: lua_State, s: const_char, len: usize);
-    pub fn lua_pushstring(L: lua_State, s: const_char);
-    pub fn lua_pushboolean(L: lua_State, b: bool);
-    pub fn lua_pushlightuserdata(L: lua_State, p: void_ptr);
-    pub fn lua_newuserdatauv(L: lua_State, size: usize, nuvalue: i32) -> void_ptr;
-    pub fn lua_pushcclosure(L: lua_State, function: lua_CFunction, n: i32);
+    pub fn lua_pushboolean(L: lua_State, b: i32);
     pub fn lua_pushvalue(L: lua_State, stack: i32);
     pub fn lua_createtable(L: lua_State, narr: i32, nrec: i32);
     pub fn lua_settop(L: lua_State, stack: i32);
     pub fn lua_setmetatable(L: lua_State, stack: i32) -> i32;
+    pub fn luaL_where(L: lua_State, level: i32);
+    pub fn lua_getmetatable(L: lua_State, stack: i32) -> i32;
+    pub fn lua_rawequal(L: lua_State, idx1: i32, idx2: i32) -> i32;
+    pub fn lua_error(L: lua_State) -> !;
+    pub fn luaL_callmeta(L: lua_State, obj: i32, e: const_char) -> i32;
+    #[cfg(any(feature = "lua51", feature = "luajit"))]
+    pub fn lua_tonumber(L: lua_State, idx: i32) -> lua_Number;
+    #[cfg(not(any(feature = "lua51", feature = "luajit")))]
+    pub fn lua_tonumberx(L: lua_State, idx: i32, isnum: *mut i32) -> lua_Number;
+    pub fn lua_next(L: lua_State, idx: i32) -> i32;
+    pub fn lua_checkstack(L: lua_State, n: i32) -> i32;
+    pub fn lua_tothread(L: lua_State, idx: i32) -> lua_State;
+    pub fn lua_pushthread(L: lua_State) -> i32;
+    pub fn lua_rawset(L: lua_State, idx: i32);
+
+    #[cfg(any(feature = "lua51", feature = "luajit"))]
+    pub fn luaL_loadbuffer(L: lua_State, buff: const_char, sz: usize, name: const_char) -> i32;
+    #[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
+    fn luaL_loadbufferx(L: lua_State, buff: const_char, sz: usize, name: const_char, mode: const_char) -> i32;
+    #[cfg(not(feature = "luau"))]
+    pub fn luaL_argerror(L: lua_State, arg: i32, extramsg: const_char) -> i32;
+    #[cfg(not(feature = "luau"))]
+    pub fn luaL_ref(L: lua_State, t: i32) -> i32;
+    #[cfg(not(feature = "luau"))]
+    pub fn luaL_unref(L: lua_State, t: i32, r: i32);
+    #[cfg(not(feature = "luau"))]
+    pub fn lua_pushlightuserdata(L: lua_State, p: void_ptr);
+    #[cfg(not(feature = "luau"))]
+    pub fn lua_pushcclosure(L: lua_State, function: lua_CFunction, n: i32);
+    #[cfg(not(feature = "luau"))]
+    pub fn lua_atpanic(L: lua_State, panicf: lua_CFunction) -> lua_CFunction;
+    #[cfg(not(feature = "luau"))]
+    pub fn lua_getstack(L: lua_State, level: i32, ar: *mut lua_Debug) -> i32;
+    #[cfg(not(feature = "luau"))]
+    pub fn lua_getinfo(L: lua_State, what: const_char, ar: *mut lua_Debug) -> i32;
+    #[cfg(not(any(feature = "lua53", feature = "lua54")))]
+    pub fn lua_insert(L: lua_State, idx: i32);
+    #[cfg(not(any(feature = "lua53", feature = "lua54")))]
+    pub fn lua_remove(L: lua_State, idx: i32);
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    pub fn lua_rotate(L: lua_State, idx: i32, n: i32);
+
+    #[cfg(any(feature = "lua51", feature = "luajit"))]
+    #[link_name = "lua_pcall"]
+    fn lua_pcall_(L: lua_State, nargs: i32, nresults: i32, errfunc: i32) -> i32;
+    #[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+    #[link_name = "lua_getfield"]
+    fn lua_getfield_(L: lua_State, stack: i32, key: const_char);
+    #[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+    #[link_name = "lua_rawgeti"]
+    fn lua_rawgeti_(L: lua_State, stack: i32, n: i32);
+    #[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+    #[link_name = "lua_rawseti"]
+    fn lua_rawseti_(L: lua_State, stack: i32, n: i32);
+    #[cfg(any(feature = "lua51", feature = "luajit"))]
+    pub fn lua_objlen(L: lua_State, idx: i32) -> usize;
+    #[cfg(any(feature = "lua51", feature = "lua52", feature = "lua53", feature = "luajit"))]
+    pub fn lua_newuserdata(L: lua_State, size: usize) -> void_ptr;
+    #[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+    #[link_name = "lua_dump"]
+    fn lua_dump_(L: lua_State, writer: lua_Writer, data: void_ptr) -> i32;
+
+    #[cfg(feature = "lua52")]
+    fn lua_pcallk(
+        L: lua_State,
+        nargs: i32,
+        nresults: i32,
+        errfunc: i32,
+        ctx: i32,
+        k: lua_CFunction,
+    ) -> i32;
+    #[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
+    pub fn lua_rawlen(L: lua_State, idx: i32) -> usize;
+
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    fn lua_pcallk(
+        L: lua_State,
+        nargs: i32,
+        nresults: i32,
+        errfunc: i32,
+        ctx: lua_KContext,
+        k: lua_CFunction,
+    ) -> i32;
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    pub fn lua_getfield(L: lua_State, stack: i32, key: const_char) -> i32;
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    pub fn lua_rawgeti(L: lua_State, stack: i32, n: lua_Integer) -> i32;
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    pub fn lua_rawseti(L: lua_State, stack: i32, n: lua_Integer);
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    pub fn lua_isinteger(L: lua_State, idx: i32) -> i32;
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    pub fn lua_tointegerx(L: lua_State, idx: i32, isnum: *mut i32) -> lua_Integer;
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
     pub fn lua_dump(L: lua_State, writer: lua_Writer, data: void_ptr, strip: i32) -> i32;
-    pub fn luaL_typeerror(L: lua_State, arg: i32, tname: *const c_char) -> i32;
+
+    #[cfg(feature = "lua54")]
+    pub fn lua_newuserdatauv(L: lua_State, size: usize, nuvalue: i32) -> void_ptr;
+
+    #[cfg(feature = "luajit")]
+    pub fn luaJIT_setmode(L: lua_State, idx: i32, mode: i32) -> i32;
+
+    #[cfg(feature = "luau")]
+    pub fn lua_pcall(L: lua_State, nargs: i32, nresults: i32, errfunc: i32) -> i32;
+    #[cfg(feature = "luau")]
+    pub fn lua_getfield(L: lua_State, stack: i32, key: const_char) -> i32;
+    #[cfg(feature = "luau")]
+    pub fn lua_rawgeti(L: lua_State, stack: i32, n: lua_Integer) -> i32;
+    #[cfg(feature = "luau")]
+    pub fn lua_rawseti(L: lua_State, stack: i32, n: lua_Integer);
+    #[cfg(feature = "luau")]
+    #[link_name = "lua_objlen"]
+    fn lua_objlen_(L: lua_State, idx: i32) -> i32;
+    #[cfg(feature = "luau")]
+    pub fn lua_newuserdatatagged(L: lua_State, size: usize, tag: i32) -> void_ptr;
+    #[cfg(feature = "luau")]
+    pub fn lua_newuserdatadtor(L: lua_State, size: usize, dtor: lua_Destructor) -> void_ptr;
+    #[cfg(feature = "luau")]
+    pub fn lua_pushlightuserdatatagged(L: lua_State, p: void_ptr, tag: i32);
+    #[cfg(feature = "luau")]
+    pub fn lua_pushcclosurek(
+        L: lua_State,
+        function: lua_CFunction,
+        debugname: *const c_char,
+        n: i32,
+        cont: lua_CFunction,
+    );
+    #[cfg(feature = "luau")]
+    pub fn lua_ref(L: lua_State, idx: i32) -> i32;
+    #[cfg(feature = "luau")]
+    pub fn lua_unref(L: lua_State, r: i32);
+    #[cfg(feature = "luau")]
+    pub fn lua_mainthread(L: lua_State) -> lua_State;
+    #[cfg(feature = "luau")]
+    pub fn lua_callbacks(L: lua_State) -> *mut lua_Callbacks;
+    #[cfg(feature = "luau")]
+    #[link_name = "lua_getinfo"]
+    fn lua_getinfo_(L: lua_State, level: i32, what: const_char, ar: *mut lua_Debug) -> i32;
+    #[cfg(feature = "luau")]
+    #[link_name = "luaL_argerrorL"]
     pub fn luaL_argerror(L: lua_State, arg: i32, extramsg: const_char) -> i32;
-    pub fn luaL_checkudata (L: lua_State, arg: i32, tname: const_char) -> void_ptr;
+    #[cfg(feature = "luau")]
+    pub fn luaL_sandbox(L: lua_State);
+    #[cfg(feature = "luau")]
+    pub fn luaL_sandboxthread(L: lua_State);
+    #[cfg(feature = "luau")]
+    pub fn luau_load(
+        L: lua_State,
+        chunkname: *const c_char,
+        data: *const c_char,
+        size: usize,
+        env: i32,
+    ) -> i32;
+    #[cfg(feature = "luau")]
+    pub fn luau_compile(
+        source: *const c_char,
+        size: usize,
+        options: void_ptr,
+        outsize: *mut usize,
+    ) -> *mut c_char;
 }
 
-pub(crate) fn luaL_argexpected(L: lua_State, cond: bool, stack: i32, tname: &str) {
-    unsafe {
-        if !cond {
-            let tname = CString::new(tname).unwrap().into_raw() as *const c_char;
-            luaL_typeerror(L, stack, tname);
-        }
+#[cfg(feature = "dynamic")]
+static LIBRARY: OnceLock<(PathBuf, libloading::Library, Api)> = OnceLock::new();
+
+#[cfg(feature = "dynamic")]
+#[inline]
+fn api() -> &'static Api {
+    match LIBRARY.get() {
+        Some((_, _, api)) => api,
+        None => panic!("{}", LibraryError::NotLoaded),
     }
 }
 
+/// Fails if no library was loaded with [`load_library`] yet.
+#[cfg(feature = "dynamic")]
+pub(crate) fn library_loaded() -> Result<(), LibraryError> {
+    match LIBRARY.get() {
+        Some(_) => Ok(()),
+        None => Err(LibraryError::NotLoaded),
+    }
+}
+
+#[cfg(feature = "dynamic")]
+unsafe fn resolve<T: Copy>(library: &libloading::Library, symbol: &'static str) -> Result<T, LibraryError> {
+    let name = format!("{symbol}\0");
+    match library.get::<T>(name.as_bytes()) {
+        Ok(function) => Ok(*function),
+        Err(_) => Err(LibraryError::MissingSymbol(symbol)),
+    }
+}
+
+/// Loads the Lua shared library at `path` and resolves the whole API from it.
+///
+/// The library is process-wide: once loaded it stays loaded, and loading a
+/// different one afterwards is an error.
+#[cfg(feature = "dynamic")]
+pub(crate) fn load_library(path: &Path) -> Result<(), LibraryError> {
+    if LIBRARY.get().is_none() {
+        let (library, api) = open_library(path)?;
+        let _ = LIBRARY.set((path.to_path_buf(), library, api));
+    }
+
+    match LIBRARY.get() {
+        Some((loaded, _, _)) if loaded != path => Err(LibraryError::AlreadyLoaded(loaded.clone())),
+        _ => Ok(()),
+    }
+}
+
+/// Opens the library at `path` and resolves the whole API from it, without
+/// making it the process-wide one.
+#[cfg(feature = "dynamic")]
+fn open_library(path: &Path) -> Result<(libloading::Library, Api), LibraryError> {
+    let library = unsafe { libloading::Library::new(path) }.map_err(|e| LibraryError::Load {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    let api = unsafe { Api::resolve(&library)? };
+    Ok((library, api))
+}
+
+/// Checks that the library at `path` could be loaded, see [`load_library`].
+#[cfg(all(test, feature = "dynamic"))]
+pub(crate) fn check_library(path: &Path) -> Result<(), LibraryError> {
+    open_library(path).map(drop)
+}
+
+// Compatibility shims: the rest of the crate is written against the 5.4 API,
+// these fill in whatever the selected version implements as a macro or under
+// a different signature.
+
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
+#[inline]
+pub unsafe fn luaL_loadbuffer(L: lua_State, buff: const_char, sz: usize, name: const_char) -> i32 {
+    luaL_loadbufferx(L, buff, sz, name, std::ptr::null_mut())
+}
+
+#[cfg(any(feature = "lua51", feature = "luajit"))]
+#[inline]
+pub unsafe fn lua_pcall(L: lua_State, nargs: i32, nresults: i32, errfunc: i32) -> i32 {
+    lua_pcall_(L, nargs, nresults, errfunc)
+}
+
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
 #[inline]
-pub(crate) fn lua_argerror(L: lua_State, arg: i32, extramsg: &str) -> i32 {
-    let extramsg = to_const_char(extramsg.to_string());
-    unsafe { luaL_argerror(L, arg, extramsg) }
+pub unsafe fn lua_pcall(L: lua_State, nargs: i32, nresults: i32, errfunc: i32) -> i32 {
+    lua_pcallk(L, nargs, nresults, errfunc, 0, std::ptr::null_mut())
 }
 
+#[cfg(any(feature = "lua51", feature = "luajit", feature = "luau"))]
 #[inline]
-pub(crate) fn luaL_checkint(L: lua_State, stack: i32) -> i32 {
-    unsafe { luaL_checkinteger(L, stack).try_into().unwrap() }
+pub unsafe fn lua_pushglobaltable(L: lua_State) {
+    lua_pushvalue(L, LUA_GLOBALSINDEX)
 }
 
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
 #[inline]
-pub(crate) fn luaL_checkunsigned(L: lua_State, stack: i32) -> u32 {
-    unsafe { luaL_checkinteger(L, stack).try_into().unwrap() }
+pub unsafe fn lua_pushglobaltable(L: lua_State) {
+    lua_rawgeti(L, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
+}
+
+#[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+#[inline]
+pub unsafe fn lua_getfield(L: lua_State, stack: i32, key: const_char) -> i32 {
+    lua_getfield_(L, stack, key);
+    lua_type(L, -1)
+}
+
+#[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+#[inline]
+pub unsafe fn lua_rawgeti(L: lua_State, stack: i32, n: lua_Integer) -> i32 {
+    lua_rawgeti_(L, stack, n as i32);
+    lua_type(L, -1)
+}
+
+#[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+#[inline]
+pub unsafe fn lua_rawseti(L: lua_State, stack: i32, n: lua_Integer) {
+    lua_rawseti_(L, stack, n as i32)
+}
+
+#[cfg(any(feature = "lua51", feature = "luajit"))]
+#[inline]
+pub unsafe fn lua_rawlen(L: lua_State, idx: i32) -> usize {
+    lua_objlen(L, idx)
+}
+
+#[cfg(feature = "luau")]
+#[inline]
+pub unsafe fn lua_rawlen(L: lua_State, idx: i32) -> usize {
+    lua_objlen_(L, idx) as usize
+}
+
+#[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit"))]
+#[inline]
+pub unsafe fn lua_dump(L: lua_State, writer: lua_Writer, data: void_ptr, _strip: i32) -> i32 {
+    lua_dump_(L, writer, data)
+}
+
+#[cfg(any(feature = "lua51", feature = "luajit", feature = "luau"))]
+#[inline]
+pub fn lua_upvalueindex(i: i32) -> i32 {
+    LUA_GLOBALSINDEX - i
+}
+
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
+#[inline]
+pub fn lua_upvalueindex(i: i32) -> i32 {
+    LUA_REGISTRYINDEX - i
+}
+
+#[cfg(any(feature = "lua53", feature = "lua54"))]
+#[inline]
+pub unsafe fn lua_insert(L: lua_State, idx: i32) {
+    lua_rotate(L, idx, 1)
+}
+
+#[cfg(not(any(feature = "lua51", feature = "luajit")))]
+#[inline]
+pub unsafe fn lua_tonumber(L: lua_State, idx: i32) -> lua_Number {
+    lua_tonumberx(L, idx, std::ptr::null_mut())
+}
+
+#[cfg(any(feature = "lua53", feature = "lua54"))]
+#[inline]
+pub unsafe fn lua_remove(L: lua_State, idx: i32) {
+    lua_rotate(L, idx, -1);
+    lua_settop(L, -2)
+}
+
+#[cfg(feature = "lua54")]
+#[inline]
+pub unsafe fn lua_newuserdata(L: lua_State, size: usize) -> void_ptr {
+    lua_newuserdatauv(L, size, 1)
+}
+
+#[cfg(feature = "luau")]
+#[inline]
+pub unsafe fn lua_newuserdata(L: lua_State, size: usize) -> void_ptr {
+    lua_newuserdatatagged(L, size, 0)
+}
+
+#[cfg(feature = "luau")]
+#[inline]
+pub unsafe fn lua_pushlightuserdata(L: lua_State, p: void_ptr) {
+    lua_pushlightuserdatatagged(L, p, 0)
+}
+
+#[cfg(feature = "luau")]
+#[inline]
+pub unsafe fn lua_pushcclosure(L: lua_State, function: lua_CFunction, n: i32) {
+    lua_pushcclosurek(L, function, std::ptr::null(), n, std::ptr::null_mut())
+}
+
+/// Pops the value on top of the stack into the registry, like `luaL_ref` does.
+/// Luau's `lua_ref` only supports the registry and leaves the value in place.
+#[cfg(feature = "luau")]
+#[inline]
+pub unsafe fn luaL_ref(L: lua_State, _t: i32) -> i32 {
+    let id = lua_ref(L, -1);
+    lua_settop(L, -2);
+    id
+}
+
+#[cfg(feature = "luau")]
+#[inline]
+pub unsafe fn luaL_unref(L: lua_State, _t: i32, r: i32) {
+    lua_unref(L, r)
+}
+
+/// Compiles the chunk to Luau bytecode and loads it. Compilation errors are
+/// encoded in the bytecode itself and reported by `luau_load`.
+#[cfg(feature = "luau")]
+pub unsafe fn luaL_loadbuffer(L: lua_State, buff: const_char, sz: usize, name: const_char) -> i32 {
+    let mut bytecode_size = 0;
+    let bytecode = luau_compile(buff, sz, std::ptr::null_mut(), &mut bytecode_size);
+    let status = luau_load(L, name, bytecode, bytecode_size, 0);
+    free(bytecode as *mut c_void);
+    match status {
+        LUA_OK => LUA_OK,
+        _ => LUA_ERRSYNTAX,
+    }
+}
+
+/// Pushes an `i64`, falling back to a float when `lua_Integer` is too narrow to hold it.
+#[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit", feature = "luau"))]
+pub(crate) fn push_long(L: lua_State, n: i64) {
+    unsafe {
+        match lua_Integer::try_from(n) {
+            Ok(n) => lua_pushinteger(L, n),
+            Err(_) => lua_pushnumber(L, n as lua_Number),
+        }
+    }
+}
+
+#[cfg(any(feature = "lua53", feature = "lua54"))]
+#[inline]
+pub(crate) fn push_long(L: lua_State, n: i64) {
+    unsafe { lua_pushinteger(L, n) }
 }
 
 #[inline]
@@ -102,49 +695,580 @@ pub(crate) fn lua_pop(L: lua_State, stack: i32) {
     unsafe { lua_settop(L, -(stack) - 1) }
 }
 
+/// The pointer kept in the userdata at `idx`, if its metatable is the one
+/// registered as `tname`. Same as `luaL_testudata`, which Lua 5.1 lacks.
+pub(crate) fn test_udata(L: lua_State, idx: i32, tname: &str) -> Option<*mut c_void> {
+    let tname = CString::new(tname).ok()?;
+    unsafe {
+        let ptr = lua_touserdata(L, idx);
+        if ptr.is_null() || lua_getmetatable(L, idx) == 0 {
+            return None;
+        }
+        lua_getfield(L, LUA_REGISTRYINDEX, tname.as_ptr() as const_char);
+        let matches = lua_rawequal(L, -1, -2) != 0;
+        lua_pop(L, 2);
+        matches.then(|| *(ptr as *mut *mut c_void))
+    }
+}
+
+/// Calls the function below the `nargs` arguments on top of the stack, with the
+/// message handler of the state installed underneath it for the duration of the call.
+/// On a poisoned state, see [`set_panic_handler`], they are popped unrun.
+pub(crate) fn pcall(L: lua_State, nargs: i32, nresults: i32) -> Result<(), LunarError> {
+    unsafe {
+        if lua_gettop(L) < nargs + 1 {
+            return Err(runtime_error("no function to call"));
+        }
+        if let Err(error) = usable(L) {
+            lua_pop(L, nargs + 1);
+            return Err(error);
+        }
+        let handler = lua_gettop(L) - nargs;
+        push_message_handler(L);
+        lua_insert(L, handler);
+
+        let status = lua_pcall(L, nargs, nresults, handler);
+        let result = if status != LUA_OK {
+            let error = error(L, status);
+            lua_pop(L, 1);
+            Err(error)
+        } else {
+            Ok(())
+        };
+
+        lua_remove(L, handler);
+        result
+    }
+}
+
+const MESSAGE_HANDLER: &CStr = c"lunar.message_handler";
+
+/// Pushes the handler set with [`set_message_handler`], or [`traceback_handler`].
+unsafe fn push_message_handler(L: lua_State) {
+    if lua_getfield(L, LUA_REGISTRYINDEX, MESSAGE_HANDLER.as_ptr() as const_char) == LUA_TNIL {
+        lua_pop(L, 1);
+        lua_pushcclosure(L, traceback_handler as *mut c_void, 0);
+    }
+}
+
+/// Replaces the message handler used by [`pcall`] with the function on top of the
+/// stack, popping it. A `nil` restores [`traceback_handler`].
+pub(crate) fn set_message_handler(L: lua_State) {
+    unsafe { lua_setfield(L, LUA_REGISTRYINDEX, MESSAGE_HANDLER.as_ptr() as const_char) }
+}
+
+/// The number at `idx`, which must be one.
+#[cfg(any(feature = "lua53", feature = "lua54"))]
+pub(crate) fn to_number(L: lua_State, idx: i32) -> Number {
+    unsafe {
+        if lua_isinteger(L, idx) != 0 {
+            Number::Integer(lua_tointegerx(L, idx, std::ptr::null_mut()))
+        } else {
+            Number::Float(lua_tonumber(L, idx))
+        }
+    }
+}
+
+#[cfg(any(feature = "lua51", feature = "lua52", feature = "luajit", feature = "luau"))]
+pub(crate) fn to_number(L: lua_State, idx: i32) -> Number {
+    Number::Float(unsafe { lua_tonumber(L, idx) })
+}
+
+/// The main thread of the state `L` belongs to, which lives as long as the state
+/// does, unlike a coroutine.
+#[cfg(any(feature = "lua52", feature = "lua53", feature = "lua54"))]
+pub(crate) fn main_thread(L: lua_State) -> lua_State {
+    unsafe {
+        lua_rawgeti(L, LUA_REGISTRYINDEX, LUA_RIDX_MAINTHREAD);
+        let main = lua_tothread(L, -1);
+        lua_pop(L, 1);
+        main
+    }
+}
+
+#[cfg(feature = "luau")]
 #[inline]
-fn lua_newuserdata(L: lua_State, size: usize) -> *mut c_void {
-    unsafe { lua_newuserdatauv(L, size, 1) }
+pub(crate) fn main_thread(L: lua_State) -> lua_State {
+    unsafe { lua_mainthread(L) }
+}
+
+/// Registry key of the main thread, which Lua 5.1 does not keep itself.
+#[cfg(any(feature = "lua51", feature = "luajit"))]
+const MAIN_THREAD: &CStr = c"lunar.main_thread";
+
+#[cfg(any(feature = "lua51", feature = "luajit"))]
+pub(crate) fn main_thread(L: lua_State) -> lua_State {
+    unsafe {
+        lua_getfield(L, LUA_REGISTRYINDEX, MAIN_THREAD.as_ptr() as const_char);
+        let main = lua_tothread(L, -1);
+        lua_pop(L, 1);
+        main
+    }
+}
+
+/// Keeps the main thread of a new state in the registry, see [`main_thread`].
+#[cfg(any(feature = "lua51", feature = "luajit"))]
+pub(crate) fn set_main_thread(L: lua_State) {
+    unsafe {
+        lua_pushthread(L);
+        lua_setfield(L, LUA_REGISTRYINDEX, MAIN_THREAD.as_ptr() as const_char);
+    }
 }
 
+const NUMBER_CONVERSION: &CStr = c"lunar.number_conversion";
 
-pub(crate) fn lua_check_udata(L: lua_State, idx: i32, tname: &str) -> *mut c_void {
+/// The conversion set with [`set_number_conversion`], or the default one.
+pub(crate) fn number_conversion(L: lua_State) -> NumberConversion {
     unsafe {
-        let tname = to_const_char(tname.to_string());
-        *(luaL_checkudata(L, idx, tname) as *mut *mut c_void)
+        lua_getfield(L, LUA_REGISTRYINDEX, NUMBER_CONVERSION.as_ptr() as const_char);
+        let conversion = match lua_tonumber(L, -1) as i32 {
+            1 => NumberConversion::Strict,
+            2 => NumberConversion::Truncating,
+            _ => NumberConversion::Lossless,
+        };
+        lua_pop(L, 1);
+        conversion
     }
 }
 
-pub(crate) fn pcall(L: lua_State, nargs: i32, nresults: i32, errfunc: i32) -> Result<(), String> {
+pub(crate) fn set_number_conversion(L: lua_State, conversion: NumberConversion) {
+    let n = match conversion {
+        NumberConversion::Lossless => 0,
+        NumberConversion::Strict => 1,
+        NumberConversion::Truncating => 2,
+    };
     unsafe {
-        if lua_pcallk(L, nargs, nresults, errfunc, 0, 0) > 0 {
-            return Err(error(L));
+        lua_pushinteger(L, n);
+        lua_setfield(L, LUA_REGISTRYINDEX, NUMBER_CONVERSION.as_ptr() as const_char);
+    }
+}
+
+/// Default message handler, attaching the call stack to the error.
+unsafe extern "C-unwind" fn traceback_handler(L: lua_State) -> i32 {
+    let mut error = error(L, LUA_ERRRUN);
+    error.set_traceback(traceback(L, 1));
+    push_error(L, error);
+    1
+}
+
+/// Frames deeper than this are left out of a traceback.
+const MAX_FRAMES: i32 = 32;
+
+/// Walks the call stack from `level`, 0 being the running function.
+pub(crate) fn traceback(L: lua_State, level: i32) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut ar: lua_Debug = unsafe { std::mem::zeroed() };
+
+    for level in level..level + MAX_FRAMES {
+        if !unsafe { get_frame(L, level, &mut ar) } {
+            break;
         }
+
+        let kind = match unsafe { c_str(ar.what) }.as_deref() {
+            Some("C") if is_rust_function(L, -1) => FrameKind::Rust,
+            Some("C") => FrameKind::C,
+            _ => FrameKind::Lua,
+        };
+        lua_pop(L, 1);
+
+        #[cfg(not(feature = "luau"))]
+        let source = unsafe { c_str(ar.short_src.as_ptr()) };
+        #[cfg(feature = "luau")]
+        let source = unsafe { c_str(ar.short_src) };
+
+        frames.push(Frame {
+            source: source.unwrap_or_default(),
+            line: u32::try_from(ar.currentline).ok().filter(|&line| line > 0),
+            name: unsafe { c_str(ar.name) },
+            kind,
+        });
+    }
+    frames
+}
+
+/// Fills `ar` with the function running at `level` and pushes that function.
+#[cfg(not(feature = "luau"))]
+unsafe fn get_frame(L: lua_State, level: i32, ar: &mut lua_Debug) -> bool {
+    lua_getstack(L, level, ar) != 0 && lua_getinfo(L, c"Slnf".as_ptr() as const_char, ar) != 0
+}
+
+/// Fills `ar` with the function running at `level` and pushes that function.
+#[cfg(feature = "luau")]
+unsafe fn get_frame(L: lua_State, level: i32, ar: &mut lua_Debug) -> bool {
+    lua_getinfo_(L, level, c"slnf".as_ptr() as const_char, ar) != 0
+}
+
+unsafe fn c_str(str: *const c_char) -> Option<String> {
+    (!str.is_null()).then(|| CStr::from_ptr(str).to_string_lossy().into_owned())
+}
+
+fn is_rust_function(L: lua_State, idx: i32) -> bool {
+    unsafe { lua_tocfunction(L, idx) == trampoline as lua_CFunction }
+}
+
+/// Turns a relative stack index into one that stays valid as values get pushed.
+pub(crate) fn absolute(L: lua_State, idx: i32) -> i32 {
+    if idx < 0 && idx > LUA_REGISTRYINDEX {
+        unsafe { lua_gettop(L) + idx + 1 }
+    } else {
+        idx
     }
-    return Ok(());
 }
 
-pub(crate) fn error(L: lua_State) -> String {
-    let str = unsafe { lua_tolstring(L, 1, std::ptr::null_mut()) };
-    match to_string(str) {
-        Ok(value) => value,
-        Err(e) => panic!("Unable to get error description.\n  {e}"),
+/// Lua calls the panic handler on an error raised outside of any protected
+/// call, and aborts the process once it returns. This one never returns: it
+/// panics with the Lua message instead, which can be caught like any panic.
+///
+/// The panic unwinds through the C frames of the failed call, which needs them
+/// to be built with unwind tables (`-funwind-tables`, or `-fexceptions`). The
+/// vendored Lua is, and Luau has them as C++. The vendored LuaJIT is not, nor
+/// may be a system or dynamically loaded library: there the unwinder finds no
+/// way out of the handler and the process aborts, as it would without it.
+///
+/// Lua does not expect the failed call to return either, and leaves the state
+/// unusable: the handler marks it as poisoned, and every entry point checks
+/// [`usable`] or [`assert_usable`] before touching it from then on.
+pub(crate) fn set_panic_handler(L: lua_State) {
+    unsafe {
+        // Set up front, so that marking the state later does not allocate.
+        lua_pushboolean(L, 0);
+        lua_setfield(L, LUA_REGISTRYINDEX, POISONED.as_ptr() as const_char);
+    }
+    #[cfg(not(feature = "luau"))]
+    unsafe {
+        lua_atpanic(L, panic_handler as lua_CFunction);
+    }
+    #[cfg(feature = "luau")]
+    unsafe {
+        (*lua_callbacks(L)).panic = Some(panic_handler);
+    }
+}
+
+const POISONED: &CStr = c"lunar.poisoned";
+
+/// Fails if an unprotected error reached the panic handler of the state.
+pub(crate) fn usable(L: lua_State) -> Result<(), LunarError> {
+    let poisoned = unsafe {
+        lua_getfield(L, LUA_REGISTRYINDEX, POISONED.as_ptr() as const_char);
+        let poisoned = lua_toboolean(L, -1) != 0;
+        lua_pop(L, 1);
+        poisoned
+    };
+    match poisoned {
+        true => Err(runtime_error("state poisoned by an unprotected error")),
+        false => Ok(()),
     }
 }
 
-pub(crate) fn to_const_char(string: String) -> const_char {
-    match CString::new(string) {
-        Ok(c_string) => c_string,
-        Err(_) => panic!("Unable to convert 'string' to 'const char*'."),
+/// Same as [`usable`] for entry points that return no error: panics instead.
+pub(crate) fn assert_usable(L: lua_State) {
+    if let Err(error) = usable(L) {
+        panic!("{error}")
     }
-    .into_raw()
 }
 
-pub(crate) fn to_string(c_str: *mut i8) -> Result<String, LunarError> {
+#[cfg(not(feature = "luau"))]
+unsafe extern "C-unwind" fn panic_handler(L: lua_State) -> i32 {
+    let error = error(L, LUA_ERRRUN);
+    lua_pop(L, 1);
+    lua_pushboolean(L, 1);
+    lua_setfield(L, LUA_REGISTRYINDEX, POISONED.as_ptr() as const_char);
+    panic!("unprotected error in call to Lua API ({error})")
+}
+
+#[cfg(feature = "luau")]
+unsafe extern "C-unwind" fn panic_handler(L: lua_State, status: i32) {
+    let error = error(L, status);
+    lua_pop(L, 1);
+    lua_pushboolean(L, 1);
+    lua_setfield(L, LUA_REGISTRYINDEX, POISONED.as_ptr() as const_char);
+    panic!("unprotected error in call to Lua API ({error})")
+}
+
+/// `t[key] = v`, with `t` at `idx` and `v` on top of the stack, which is popped.
+/// Unlike `lua_setfield`, an error raised by a `__newindex` metamethod is
+/// returned instead of reaching the panic handler.
+pub(crate) fn protected_setfield(L: lua_State, idx: i32, key: &str) -> Result<(), LunarError> {
+    unsafe {
+        let idx = absolute(L, idx);
+        lua_pushcclosure(L, setfield as *mut c_void, 0);
+        lua_pushvalue(L, idx);
+        lua_pushlstring(L, key.as_ptr() as const_char, key.len());
+        lua_pushvalue(L, -4);
+
+        let result = pcall(L, 3, 0);
+        lua_pop(L, 1);
+        result
+    }
+}
+
+unsafe extern "C-unwind" fn setfield(L: lua_State) -> i32 {
+    lua_settable(L, 1);
+    0
+}
+
+/// Same as [`protected_setfield`] for the global `name`.
+pub(crate) fn protected_setglobal(L: lua_State, name: &str) -> Result<(), LunarError> {
+    unsafe {
+        lua_pushcclosure(L, setglobal as *mut c_void, 0);
+        lua_pushlstring(L, name.as_ptr() as const_char, name.len());
+        lua_pushvalue(L, -3);
+
+        let result = pcall(L, 2, 0);
+        lua_pop(L, 1);
+        result
+    }
+}
+
+unsafe extern "C-unwind" fn setglobal(L: lua_State) -> i32 {
+    lua_pushglobaltable(L);
+    lua_insert(L, 1);
+    lua_settable(L, 1);
+    0
+}
+
+/// Pushes `t[key]`, with `t` at `idx`. Unlike `lua_getfield`, an error raised
+/// by an `__index` metamethod is returned, and nothing is pushed then.
+pub(crate) fn protected_getfield(L: lua_State, idx: i32, key: &str) -> Result<(), LunarError> {
+    unsafe {
+        let idx = absolute(L, idx);
+        lua_pushcclosure(L, getfield as *mut c_void, 0);
+        lua_pushvalue(L, idx);
+        lua_pushlstring(L, key.as_ptr() as const_char, key.len());
+        pcall(L, 2, 1)
+    }
+}
+
+unsafe extern "C-unwind" fn getfield(L: lua_State) -> i32 {
+    lua_gettable(L, 1);
+    1
+}
+
+/// Same as [`protected_getfield`] for the global `name`.
+pub(crate) fn protected_getglobal(L: lua_State, name: &str) -> Result<(), LunarError> {
+    unsafe {
+        lua_pushcclosure(L, getglobal as *mut c_void, 0);
+        lua_pushlstring(L, name.as_ptr() as const_char, name.len());
+        pcall(L, 1, 1)
+    }
+}
+
+unsafe extern "C-unwind" fn getglobal(L: lua_State) -> i32 {
+    lua_pushglobaltable(L);
+    lua_insert(L, 1);
+    lua_gettable(L, 1);
+    1
+}
+
+/// Pushes the global `name`, like `lua_getglobal` but for any name, embedded
+/// zeros included. Unprotected: an `__index` metamethod on the globals table
+/// must not raise.
+pub(crate) fn push_global(L: lua_State, name: &str) {
+    assert_usable(L);
+    unsafe {
+        lua_pushglobaltable(L);
+        lua_pushlstring(L, name.as_ptr() as const_char, name.len());
+        lua_gettable(L, -2);
+        lua_remove(L, -2);
+    }
+}
+
+/// Loads `chunk` as a function on top of the stack, or pops the compile error.
+pub(crate) fn load_chunk(L: lua_State, chunk: String) -> Result<(), LunarError> {
+    // Named after its source like `luaL_loadstring` does, which only shows the
+    // start of it anyway.
+    usable(L)?;
+    let end = chunk.find('\0').unwrap_or(chunk.len());
+    let name = CString::new(&chunk[..end]).unwrap_or_default();
+    unsafe {
+        let status = luaL_loadbuffer(L, chunk.as_ptr() as const_char, chunk.len(), name.as_ptr() as const_char);
+        if status != LUA_OK {
+            let error = error(L, status);
+            lua_pop(L, 1);
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Converts the error value on top of the stack, leaving it there.
+pub(crate) fn error(L: lua_State, status: i32) -> LunarError {
+    if let Some(error) = to_error(L, -1) {
+        return error;
+    }
+
+    let kind = unsafe { lua_type(L, -1) };
+    if kind == LUA_TSTRING || kind == LUA_TNUMBER {
+        let message = unsafe { c_str(lua_tolstring(L, -1, std::ptr::null_mut())) };
+        return LunarError::from_lua(status, message.unwrap_or_default());
+    }
+
+    let message = tostring(L, -1).unwrap_or_else(|| {
+        let typename = unsafe { c_str(lua_typename(L, kind)) }.unwrap_or_default();
+        format!("(error object is a {typename} value)")
+    });
+    LunarError::Value {
+        value: LunarContext::new(L).get_value(-1),
+        snapshot: error_value(L, -1, ERROR_VALUE_DEPTH),
+        message,
+        traceback: Box::default(),
+    }
+}
+
+/// Levels of nested tables copied into an [`ErrorValue`].
+const ERROR_VALUE_DEPTH: u32 = 4;
+
+/// Copies the value at `idx`, tables read raw, so that nothing can raise.
+fn error_value(L: lua_State, idx: i32, depth: u32) -> ErrorValue {
+    unsafe {
+        match lua_type(L, idx) {
+            LUA_TNIL => ErrorValue::Nil,
+            LUA_TBOOLEAN => ErrorValue::Bool(lua_toboolean(L, idx) != 0),
+            LUA_TNUMBER => ErrorValue::Number(to_number(L, idx)),
+            LUA_TSTRING => {
+                let mut len = 0;
+                let ptr = lua_tolstring(L, idx, &mut len);
+                let bytes = std::slice::from_raw_parts(ptr as *const u8, len);
+                ErrorValue::String(String::from_utf8_lossy(bytes).into_owned())
+            }
+            LUA_TTABLE if depth > 0 && lua_checkstack(L, 2) != 0 => {
+                let idx = absolute(L, idx);
+                let mut pairs = Vec::new();
+                lua_pushnil(L);
+                while lua_next(L, idx) != 0 {
+                    pairs.push((error_value(L, -2, depth - 1), error_value(L, -1, depth - 1)));
+                    lua_pop(L, 1);
+                }
+                ErrorValue::Table(pairs)
+            }
+            kind => ErrorValue::Other(c_str(lua_typename(L, kind)).unwrap_or_default()),
+        }
+    }
+}
+
+/// Result of the `__tostring` metamethod of the value at `idx`, if it has one
+/// that succeeds.
+fn tostring(L: lua_State, idx: i32) -> Option<String> {
+    unsafe {
+        let idx = absolute(L, idx);
+        lua_pushcclosure(L, call_tostring as *mut c_void, 0);
+        lua_pushvalue(L, idx);
+
+        // Not `pcall`: this runs inside the message handler already.
+        let message = match lua_pcall(L, 1, 1, 0) {
+            LUA_OK if lua_type(L, -1) == LUA_TSTRING => c_str(lua_tolstring(L, -1, std::ptr::null_mut())),
+            _ => None,
+        };
+        lua_pop(L, 1);
+        message
+    }
+}
+
+unsafe extern "C-unwind" fn call_tostring(L: lua_State) -> i32 {
+    luaL_callmeta(L, 1, c"__tostring".as_ptr() as const_char)
+}
+
+/// Location of the function running at `level`, as `luaL_where` reports it.
+pub(crate) fn location(L: lua_State, level: i32) -> (Option<String>, Option<u32>) {
+    unsafe {
+        luaL_where(L, level);
+        let str = lua_tolstring(L, -1, std::ptr::null_mut());
+        let location = CStr::from_ptr(str).to_string_lossy().into_owned();
+        lua_pop(L, 1);
+
+        let (chunk, line, _) = split_location(&location);
+        (chunk, line)
+    }
+}
+
+const ERROR_METATABLE: &CStr = c"lunar.error";
+
+/// Pushes `error` as a userdata, so that it travels through Lua untouched and
+/// comes back as is from [`pcall`].
+pub(crate) fn push_error(L: lua_State, error: LunarError) {
+    unsafe {
+        let error = Box::into_raw(Box::new(error));
+        let size = std::mem::size_of::<*mut LunarError>();
+
+        #[cfg(not(feature = "luau"))]
+        let ptr = lua_newuserdata(L, size) as *mut *mut LunarError;
+        #[cfg(feature = "luau")]
+        let ptr = lua_newuserdatadtor(L, size, error_dtor) as *mut *mut LunarError;
+
+        *ptr = error;
+        push_error_metatable(L);
+        lua_setmetatable(L, -2);
+    }
+}
+
+/// Returns a copy of the value at `idx` if it was pushed by [`push_error`].
+pub(crate) fn to_error(L: lua_State, idx: i32) -> Option<LunarError> {
+    unsafe {
+        let idx = absolute(L, idx);
+        if lua_type(L, idx) != LUA_TUSERDATA || lua_getmetatable(L, idx) == 0 {
+            return None;
+        }
+
+        push_error_metatable(L);
+        let is_error = lua_rawequal(L, -1, -2) != 0;
+        lua_pop(L, 2);
+
+        if !is_error {
+            return None;
+        }
+        let ptr = *(lua_touserdata(L, idx) as *mut *mut LunarError);
+        ptr.as_ref().cloned()
+    }
+}
+
+unsafe fn push_error_metatable(L: lua_State) {
+    if lua_getfield(L, LUA_REGISTRYINDEX, ERROR_METATABLE.as_ptr() as const_char) != LUA_TNIL {
+        return;
+    }
+    lua_pop(L, 1);
+
+    lua_createtable(L, 0, 2);
+    lua_pushcclosure(L, error_tostring as *mut c_void, 0);
+    lua_setfield(L, -2, c"__tostring".as_ptr() as const_char);
+    #[cfg(not(feature = "luau"))]
+    {
+        lua_pushcclosure(L, error_gc as *mut c_void, 0);
+        lua_setfield(L, -2, c"__gc".as_ptr() as const_char);
+    }
+
+    lua_pushvalue(L, -1);
+    lua_setfield(L, LUA_REGISTRYINDEX, ERROR_METATABLE.as_ptr() as const_char);
+}
+
+unsafe extern "C-unwind" fn error_tostring(L: lua_State) -> i32 {
+    let ptr = *(lua_touserdata(L, 1) as *mut *mut LunarError);
+    let message = match ptr.as_ref() {
+        Some(error) => error.to_string(),
+        None => String::new(),
+    };
+    lua_pushlstring(L, message.as_ptr() as const_char, message.len());
+    1
+}
+
+#[cfg(not(feature = "luau"))]
+unsafe extern "C-unwind" fn error_gc(L: lua_State) -> i32 {
+    error_dtor(lua_touserdata(L, 1));
+    0
+}
+
+unsafe extern "C" fn error_dtor(ud: void_ptr) {
+    let ptr = ud as *mut *mut LunarError;
+    if !(*ptr).is_null() {
+        drop(Box::from_raw(*ptr));
+        *ptr = std::ptr::null_mut();
+    }
+}
+
+pub(crate) fn to_string(c_str: const_char) -> Result<String, LunarError> {
     unsafe {
         match CStr::from_ptr(c_str).to_str() {
             Ok(str) => Ok(String::from(str)),
-            Err(_) => Err("Unable to convert 'const char*' to 'string'."),
+            Err(e) => Err(LunarError::Utf8(e)),
         }
     }
 }
@@ -166,10 +1290,190 @@ pub(crate) fn lua_get_lightuserdata(L: lua_State, idx: i32) -> *mut c_void {
     unsafe { lua_touserdata(L, idx) }
 }
 
+/// Pushes a Rust callback as a closure of [`trampoline`], which keeps the function
+/// pointer and the [`invoke`] matching its return type as upvalues.
+pub(crate) fn push_function<R: CallbackResult>(L: lua_State, function: fn(LunarContext) -> R) {
+    let invoke: Invoke = invoke::<R>;
+    unsafe {
+        lua_pushlightuserdata(L, function as void_ptr);
+        lua_pushlightuserdata(L, invoke as void_ptr);
+        lua_pushcclosure(L, trampoline as *mut c_void, 2);
+    }
+}
+
+/// A Rust closure pushed with [`push_closure`].
+pub(crate) type Closure = dyn Fn(LunarContext) -> Result<i32, LunarError>;
+
+const CLOSURE_METATABLE: &CStr = c"lunar.closure";
 
-pub(crate) fn push_function(L: lua_State, function: *const ()) {
+/// Pushes a Rust closure as a closure of [`trampoline`]. The closure lives in a
+/// userdata upvalue, and is dropped when Lua collects that userdata.
+pub(crate) fn push_closure(L: lua_State, closure: Box<Closure>) {
+    let invoke: Invoke = invoke_closure;
     unsafe {
-        let function: extern "C" fn(L: lua_State) = std::mem::transmute(function);
-        lua_pushcclosure(L, function as *mut c_void, 0);
+        let closure = Box::into_raw(Box::new(closure));
+        let size = std::mem::size_of::<*mut Box<Closure>>();
+
+        #[cfg(not(feature = "luau"))]
+        let ptr = lua_newuserdata(L, size) as *mut *mut Box<Closure>;
+        #[cfg(feature = "luau")]
+        let ptr = lua_newuserdatadtor(L, size, closure_dtor) as *mut *mut Box<Closure>;
+
+        *ptr = closure;
+        push_closure_metatable(L);
+        lua_setmetatable(L, -2);
+
+        lua_pushlightuserdata(L, invoke as void_ptr);
+        lua_pushcclosure(L, trampoline as *mut c_void, 2);
+    }
+}
+
+unsafe fn push_closure_metatable(L: lua_State) {
+    if lua_getfield(L, LUA_REGISTRYINDEX, CLOSURE_METATABLE.as_ptr() as const_char) != LUA_TNIL {
+        return;
+    }
+    lua_pop(L, 1);
+
+    lua_createtable(L, 0, 2);
+    // Keeps scripts from reaching `__gc` through `debug.getupvalue`.
+    lua_pushboolean(L, 0);
+    lua_setfield(L, -2, c"__metatable".as_ptr() as const_char);
+    #[cfg(not(feature = "luau"))]
+    {
+        lua_pushcclosure(L, closure_gc as *mut c_void, 0);
+        lua_setfield(L, -2, c"__gc".as_ptr() as const_char);
+    }
+
+    lua_pushvalue(L, -1);
+    lua_setfield(L, LUA_REGISTRYINDEX, CLOSURE_METATABLE.as_ptr() as const_char);
+}
+
+#[cfg(not(feature = "luau"))]
+unsafe extern "C-unwind" fn closure_gc(L: lua_State) -> i32 {
+    closure_dtor(lua_touserdata(L, 1));
+    0
+}
+
+unsafe extern "C" fn closure_dtor(ud: void_ptr) {
+    let ptr = ud as *mut *mut Box<Closure>;
+    if !(*ptr).is_null() {
+        drop(Box::from_raw(*ptr));
+        *ptr = std::ptr::null_mut();
+    }
+}
+
+unsafe fn invoke_closure(userdata: void_ptr, ctx: LunarContext) -> Result<i32, LunarError> {
+    match (*(userdata as *mut *mut Box<Closure>)).as_ref() {
+        Some(closure) => closure(ctx),
+        None => Err(callback_error(ctx.L(), "attempt to call a collected closure")),
+    }
+}
+
+/// A [`LunarError::Runtime`] raised by Lunar itself, not located in any chunk.
+pub(crate) fn runtime_error(message: impl Into<String>) -> LunarError {
+    LunarError::Runtime {
+        chunk: None,
+        line: None,
+        message: message.into(),
+        traceback: Box::default(),
+    }
+}
+
+/// A [`LunarError::Callback`] located at the Lua code that called the callback.
+pub(crate) fn callback_error(L: lua_State, message: impl Into<String>) -> LunarError {
+    let (chunk, line) = location(L, 1);
+    LunarError::Callback {
+        chunk,
+        line,
+        message: message.into(),
+        traceback: Box::default(),
+    }
+}
+
+type Invoke = unsafe fn(void_ptr, LunarContext) -> Result<i32, LunarError>;
+
+unsafe fn invoke<R: CallbackResult>(function: void_ptr, ctx: LunarContext) -> Result<i32, LunarError> {
+    let function: fn(LunarContext) -> R = std::mem::transmute(function);
+    function(ctx).into_result()
+}
+
+/// An error raised from a Rust callback by unwinding, see [`raise`]. It holds no
+/// [`LunarError`], which may hold values of the state and cannot be sent as a
+/// panic payload.
+pub(crate) enum Raised {
+    /// The message of a [`LunarError::Callback`].
+    Callback(String),
+    /// A bad argument and the reason, raised with `luaL_argerror` so that Lua
+    /// names the function the way it does for its own.
+    Argument(i32, String),
+}
+
+/// Raises a [`LunarError::Callback`] from the Rust callback running. Unlike
+/// `lua_error`, which would jump over the Rust frames in between without dropping
+/// anything they own, this unwinds up to [`trampoline`], which raises the error
+/// once they are gone.
+pub(crate) fn raise(message: impl Into<String>) -> ! {
+    panic::resume_unwind(Box::new(Raised::Callback(message.into())))
+}
+
+/// Same as [`raise`] for a `bad argument` error on argument `arg`.
+pub(crate) fn raise_argument(arg: i32, error: LunarError) -> ! {
+    let message = match error {
+        LunarError::Conversion { expected, found, message: None } => format!("{expected} expected, got {found}"),
+        error => error.to_string(),
+    };
+    panic::resume_unwind(Box::new(Raised::Argument(arg, message)))
+}
+
+/// Calls the Rust callback in the upvalues. A panic must not unwind into the Lua
+/// frames below, so it is caught here and raised again as a Lua error, reported
+/// as [`LunarError::CallbackPanic`]. Errors returned by the callback, or unwound
+/// with [`raise`], are raised as they are.
+unsafe extern "C-unwind" fn trampoline(L: lua_State) -> i32 {
+    let function = lua_touserdata(L, lua_upvalueindex(1));
+    let invoke: Invoke = std::mem::transmute(lua_touserdata(L, lua_upvalueindex(2)));
+
+    let raised = match panic::catch_unwind(|| invoke(function, LunarContext::new(L))) {
+        Ok(Ok(results)) => return results,
+        Ok(Err(error)) => Err(error),
+        Err(payload) => match payload.downcast::<Raised>().map(|raised| *raised) {
+            Ok(Raised::Callback(message)) => Err(callback_error(L, message)),
+            Ok(Raised::Argument(arg, message)) => Ok((arg, message)),
+            Err(payload) => Err(panic_error(L, payload)),
+        },
+    };
+
+    let argument = match raised {
+        Err(error) => {
+            push_error(L, error);
+            None
+        }
+        Ok((arg, message)) => {
+            lua_pushlstring(L, message.as_ptr() as const_char, message.len());
+            Some(arg)
+        }
+    };
+
+    // Nothing owned may be left in this frame, neither call returns.
+    match argument {
+        Some(arg) => luaL_argerror(L, arg, lua_tolstring(L, -1, std::ptr::null_mut())),
+        None => lua_error(L),
+    }
+}
+
+fn panic_error(L: lua_State, payload: Box<dyn std::any::Any + Send>) -> LunarError {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "Box<dyn Any>".to_string(),
+        },
+    };
+    let (chunk, line) = location(L, 1);
+    LunarError::CallbackPanic {
+        chunk,
+        line,
+        message,
+        traceback: Box::default(),
     }
 }
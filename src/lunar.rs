@@ -1,13 +1,17 @@
 #![allow(dead_code)]
 
-use std::rc::Rc;
+use std::{cell::RefCell, rc::Rc};
 
 use crate::{
-    context::{LunarContext, Value},
+    context::{CallbackResult, Function, LunarContext, Value},
+    convert::{mismatch, FromLua, FromLuaMulti, IntoLua, IntoLuaMulti},
+    error::LunarError,
+    function::{call_pushed, LuaFunction, TypedFunction},
     lua::*,
     metatable::{MetaTable, MetaMethod},
     state::State,
     table::Table,
+    types::{LuaType, NumberConversion},
 };
 
 
@@ -16,50 +20,197 @@ pub struct Lunar {
     lua: State,
 }
 
+impl Default for Lunar {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Lunar {
+    /// Creates a state.
+    ///
+    /// With the `dynamic` feature, this panics if no Lua library was loaded
+    /// yet: create the first state with [`with_library`](Lunar::with_library),
+    /// or use [`try_new`](Lunar::try_new).
     #[inline]
     pub fn new() -> Self {
         Self { lua: State::new() }
     }
 
+    /// Same as [`new`](Lunar::new), returning [`LibraryError::NotLoaded`](crate::error::LibraryError::NotLoaded)
+    /// if no Lua library was loaded yet.
+    #[cfg(feature = "dynamic")]
+    pub fn try_new() -> Result<Self, LunarError> {
+        library_loaded()?;
+        Ok(Self::new())
+    }
+
+    /// Creates a state from the Lua shared library at `path`, loaded at runtime.
+    ///
+    /// Every function of the Lua API is resolved up front, so a library that is
+    /// missing one is rejected here. The library is shared by the whole process:
+    /// later states can be created with [`Lunar::new`] or with the same path.
+    #[cfg(feature = "dynamic")]
+    pub fn with_library<P: AsRef<std::path::Path>>(path: P) -> Result<Self, LunarError> {
+        load_library(path.as_ref())?;
+        Ok(Self::new())
+    }
+
     #[inline]
     pub fn load_std_library(&self) {
+        assert_usable(self.lua.L());
         unsafe { luaL_openlibs(self.lua.L()) }
     }
 
-    #[inline]
-    pub fn exec(&self) -> Result<(), String> {
-        pcall(self.lua.L(), 0, 0, 0)
+    /// Runs the chunk loaded last. Without one, e.g. after a failed
+    /// [`load`](Lunar::load), nothing runs and an error is returned.
+    pub fn exec(&self) -> Result<(), LunarError> {
+        let ctx = LunarContext::new(self.lua.L());
+        if ctx.stack_size() == 0 || ctx.get_type(-1) != LuaType::Function {
+            return Err(runtime_error("no chunk loaded"));
+        }
+        pcall(self.lua.L(), 0, 0)
+    }
+
+    /// Replaces the message handler of `exec`, `call_global_function` and
+    /// [`LunarContext::call_function`]. It receives the error value and returns
+    /// the one to report instead.
+    ///
+    /// By default the error gets a [traceback](LunarError::traceback) attached.
+    pub fn set_message_handler(&self, handler: Function) {
+        assert_usable(self.lua.L());
+        push_function(self.lua.L(), handler);
+        set_message_handler(self.lua.L());
     }
 
+    /// Sets how the typed getters of this state convert between integers and
+    /// floats. [`NumberConversion::Lossless`] until set.
+    pub fn set_number_conversion(&self, conversion: NumberConversion) {
+        assert_usable(self.lua.L());
+        set_number_conversion(self.lua.L(), conversion)
+    }
+
+    /// Loads a chunk to be run by the next [`exec`](Lunar::exec).
+    ///
+    /// A chunk that does not compile is reported as [`LunarError::Syntax`] and
+    /// leaves nothing on the stack.
     #[inline]
-    pub fn load_string(&self, string: String) {
-        unsafe {
-            luaL_loadstring(self.lua.L(), to_const_char(string));
-        }
+    pub fn load_string(&self, string: String) -> Result<(), LunarError> {
+        load_chunk(self.lua.L(), string)
     }
 
+    /// Same as [`load_string`](Lunar::load_string).
     #[inline]
-    pub fn load(&self, script: &str) {
-        unsafe {
-            luaL_loadstring(self.lua.L(), to_const_char(script.to_string()));
-        }
+    pub fn load(&self, script: &str) -> Result<(), LunarError> {
+        load_chunk(self.lua.L(), script.to_string())
     }
 
-    pub fn create_global_value(&self, name: &str, value: Value){
-        unsafe {
-            let ctx = LunarContext::new(self.lua.L());
-            ctx.push(value);
-            lua_setglobal(self.lua.L(), to_const_char(name.to_string()));
-        }
+    /// Compiles a chunk into a function instead of queueing it for [`exec`](Lunar::exec),
+    /// so that it can be called any number of times. The arguments of a call are
+    /// reachable from the chunk as `...`.
+    pub fn load_function(&self, script: &str) -> Result<LuaFunction, LunarError> {
+        load_chunk(self.lua.L(), script.to_string())?;
+        Ok(self.pop_function())
     }
 
-    pub fn create_static_function(&self, name: &str, function: fn(ctx: LunarContext) -> i32) {
-        unsafe {
-            let function = function as *const ();
-            push_function(self.lua.L(), function);
-            lua_setglobal(self.lua.L(), to_const_char(name.to_string()));
-        }
+    #[cfg(test)]
+    pub(crate) fn context(&self) -> Rc<LunarContext> {
+        Rc::new(LunarContext::new(self.lua.L()))
+    }
+
+    /// Sets the global `name`. An error raised by a metamethod of the globals
+    /// table, e.g. once sandboxed, is returned.
+    pub fn create_global_value<T: IntoLua>(&self, name: &str, value: T) -> Result<(), LunarError> {
+        usable(self.lua.L())?;
+        let ctx = LunarContext::new(self.lua.L());
+        value.push_into(&ctx);
+        protected_setglobal(self.lua.L(), name)
+    }
+
+    /// Reads the global `name` as a `T`. An error raised by a metamethod of the
+    /// globals table, or a value that does not convert, is returned.
+    pub fn get_global<T: FromLua>(&self, name: &str) -> Result<T, LunarError> {
+        let ctx = LunarContext::new(self.lua.L());
+        protected_getglobal(self.lua.L(), name)?;
+        let value = T::from_lua(&ctx, -1);
+        ctx.pop_last();
+        value
+    }
+
+    /// Sets the global `name` to a Rust callback. An error raised by a
+    /// metamethod of the globals table is returned, as with [`create_global_value`](Lunar::create_global_value).
+    ///
+    /// The callback may return a `Result`, whose error is raised in Lua, or
+    /// nothing, see [`CallbackResult`].
+    pub fn create_static_function<R: CallbackResult>(
+        &self,
+        name: &str,
+        function: fn(ctx: LunarContext) -> R,
+    ) -> Result<(), LunarError> {
+        usable(self.lua.L())?;
+        push_function(self.lua.L(), function);
+        protected_setglobal(self.lua.L(), name)
+    }
+
+    /// Creates a Lua function from a Rust closure, which may capture state such as
+    /// a database handle or an `Arc<Mutex<_>>`. The closure is dropped once Lua
+    /// collects the function.
+    ///
+    /// The function is not bound to any name: pass it to [`create_global_value`](Lunar::create_global_value)
+    /// or [`Table::set`], or as [`Value::LuaFunction`] where a `Value` is expected.
+    pub fn create_function<F, R>(&self, function: F) -> LuaFunction
+    where
+        F: Fn(LunarContext) -> R + 'static,
+        R: CallbackResult,
+    {
+        assert_usable(self.lua.L());
+        push_closure(self.lua.L(), Box::new(move |ctx| function(ctx).into_result()));
+        self.pop_function()
+    }
+
+    /// Same as [`create_function`](Lunar::create_function) for a closure that
+    /// mutates its state. Calling the function again from inside itself, e.g.
+    /// through a Lua callback it calls, raises an error.
+    pub fn create_function_mut<F, R>(&self, function: F) -> LuaFunction
+    where
+        F: FnMut(LunarContext) -> R + 'static,
+        R: CallbackResult,
+    {
+        assert_usable(self.lua.L());
+        let function = RefCell::new(function);
+        push_closure(
+            self.lua.L(),
+            Box::new(move |ctx| match function.try_borrow_mut() {
+                Ok(mut function) => function(ctx).into_result(),
+                Err(_) => Err(callback_error(ctx.L(), "mutable closure called recursively")),
+            }),
+        );
+        self.pop_function()
+    }
+
+    /// Registers a Rust function as the global `name`, reading its arguments and
+    /// pushing its results through the conversion traits, e.g.
+    /// `lunar.register("add", |a: i32, b: i32| a + b)`.
+    ///
+    /// Calling it with an argument that does not convert raises a `bad argument`
+    /// error naming the function and the position, and calling it with more
+    /// arguments than it takes raises an error too. Missing arguments are read
+    /// as `nil`, so trailing `Option` parameters may be left out.
+    ///
+    /// An error raised by a metamethod of the globals table is returned.
+    pub fn register<A, F: TypedFunction<A>>(&self, name: &str, function: F) -> Result<(), LunarError> {
+        usable(self.lua.L())?;
+        let function_name = name.to_string();
+        push_closure(self.lua.L(), Box::new(move |ctx| function.call_typed(&ctx, &function_name)));
+        protected_setglobal(self.lua.L(), name)
+    }
+
+    fn pop_function(&self) -> LuaFunction {
+        let ctx = LunarContext::new(self.lua.L());
+        let function = LuaFunction::from_stack(&ctx, -1);
+        ctx.pop_last();
+        function
     }
 
     pub fn create_table(&self, name: &str, global: bool, table: fn(Table)) {
@@ -67,11 +218,104 @@ impl Lunar {
         table(Table::new(ctx, name, global))
     }
 
-    pub fn call_global_function(&self, name: &str, args: Vec<Value>, nresult: i32){
-        let ctx = Rc::new(LunarContext::new(self.lua.L()));
-        ctx.get_global(name);
-        let function_stack = ctx.stack_size();
-        ctx.call_function(function_stack, args, nresult);
+    /// Calls the global function `name` in protected mode, and returns `nresult`
+    /// results, cut or padded with `nil` as Lua does, or all of them if `nresult`
+    /// is negative. The stack is left as it was, whether the call failed or not.
+    pub fn call_global_function(&self, name: &str, args: Vec<Value>, nresult: i32) -> Result<Vec<Value>, LunarError> {
+        let ctx = LunarContext::new(self.lua.L());
+        let base = ctx.stack_size();
+        self.push_global_function(&ctx, name)?;
+
+        // One more slot for the message handler `pcall` installs.
+        let nargs = args.len() as i32;
+        if unsafe { lua_checkstack(self.lua.L(), nargs + 1) } == 0 {
+            ctx.pop_last();
+            return Err(LunarError::Memory(format!("stack overflow ({nargs} arguments to '{name}')")));
+        }
+        for value in args {
+            ctx.push(value);
+        }
+        pcall(self.lua.L(), nargs, nresult.max(LUA_MULTRET))?;
+
+        let results = (base + 1..=ctx.stack_size()).map(|idx| ctx.get_value(idx)).collect();
+        unsafe { lua_settop(self.lua.L(), base) };
+        Ok(results)
+    }
+
+    /// Same as [`call_global_function`](Lunar::call_global_function), converting
+    /// the arguments and results, e.g. `lunar.call_global::<_, i64>("add", (2, 3))`.
+    pub fn call_global<A: IntoLuaMulti, R: FromLuaMulti>(&self, name: &str, args: A) -> Result<R, LunarError> {
+        let ctx = LunarContext::new(self.lua.L());
+        self.push_global_function(&ctx, name)?;
+        call_pushed(&ctx, args)
+    }
+
+    /// Pushes the global `name`, or returns an error if it is not a function.
+    fn push_global_function(&self, ctx: &LunarContext, name: &str) -> Result<(), LunarError> {
+        protected_getglobal(self.lua.L(), name)?;
+        if ctx.get_type(-1) == LuaType::Function {
+            return Ok(());
+        }
+
+        let error = match mismatch(ctx, -1, "function") {
+            LunarError::Conversion { expected, found, .. } => LunarError::Conversion {
+                expected,
+                found,
+                message: Some(format!("global '{name}' is not a function")),
+            },
+            error => error,
+        };
+        ctx.pop_last();
+        Err(error)
+    }
+
+    /// Turns the JIT compiler on, same as `jit.on()`.
+    ///
+    /// Returns `false` if the JIT compiler is not available on this platform.
+    #[cfg(feature = "luajit")]
+    pub fn jit_on(&self) -> bool {
+        self.jit_mode(LUAJIT_MODE_ON)
+    }
+
+    /// Turns the JIT compiler off, same as `jit.off()`. Compiled code is kept until flushed.
+    #[cfg(feature = "luajit")]
+    pub fn jit_off(&self) -> bool {
+        self.jit_mode(LUAJIT_MODE_OFF)
+    }
+
+    /// Flushes the whole cache of compiled code, same as `jit.flush()`.
+    #[cfg(feature = "luajit")]
+    pub fn jit_flush(&self) -> bool {
+        self.jit_mode(LUAJIT_MODE_FLUSH)
+    }
+
+    #[cfg(feature = "luajit")]
+    fn jit_mode(&self, mode: i32) -> bool {
+        assert_usable(self.lua.L());
+        unsafe { luaJIT_setmode(self.lua.L(), 0, LUAJIT_MODE_ENGINE | mode) != 0 }
+    }
+
+    /// Sandboxes the state, same as Luau's `luaL_sandbox`.
+    ///
+    /// The built-in libraries and the global table become read-only, and the
+    /// state is marked as a safe environment so the VM can optimize global access.
+    /// Globals should be registered before sandboxing, or a writable environment
+    /// created afterwards with [`Lunar::sandbox_thread`].
+    #[cfg(feature = "luau")]
+    pub fn sandbox(&self) {
+        assert_usable(self.lua.L());
+        unsafe { luaL_sandbox(self.lua.L()) }
+    }
+
+    /// Gives the state a fresh global table that falls back to the sandboxed
+    /// one, same as Luau's `luaL_sandboxthread`.
+    ///
+    /// Scripts can then create globals freely without touching the shared,
+    /// read-only environment.
+    #[cfg(feature = "luau")]
+    pub fn sandbox_thread(&self) {
+        assert_usable(self.lua.L());
+        unsafe { luaL_sandboxthread(self.lua.L()) }
     }
 
     pub fn register_userdata(&self, name: &str, data: fn(&MetaTable)) {
@@ -82,11 +326,11 @@ impl Lunar {
 
         methods.add_meta_method(MetaMethod::NewIndex, Value::Function(|_|{
             println!("attempt to update a read-only table");
-            return 0;
+            0
         }));
 
-        class.push_table();
-        methods.push_metatable();
+        class.push_table(ctx.L());
+        methods.push_metatable(ctx.L());
         ctx.set_field("__index", 1);
         class.set_metatable(&methods);
 
@@ -1,7 +1,10 @@
+#![allow(non_snake_case)]
+
 use std::rc::Rc;
 
 use crate::{
-    context::{Function, LunarContext, Value},
+    context::{CallbackResult, Function, LunarContext, Value},
+    lua::{lua_State, push_function},
     table::Table,
 };
 
@@ -17,19 +20,21 @@ impl MetaTable {
     }
 
     pub fn constructor(&self, func: Function) {
-        self.add_meta_method(MetaMethod::Call, Value::Function(func));
+        self.table.init_field(MetaMethod::Call.name(), |ctx| push_function(ctx.L(), func));
     }
 
-    pub fn add_method(&self, name: &str, method: Function) {
-        self.table.set(name, Value::Function(method));
+    /// Adds the method `name`. It may return a `Result`, or nothing, see
+    /// [`CallbackResult`].
+    pub fn add_method<R: CallbackResult>(&self, name: &str, method: fn(LunarContext) -> R) {
+        self.table.init_field(name, |ctx| push_function(ctx.L(), method));
     }
 
     pub fn add_meta_method(&self, metamethod: MetaMethod, value: Value) {
-        self.table.set(metamethod.name(), value)
+        self.table.init_field(metamethod.name(), |ctx| ctx.push(value))
     }
 
-    pub(crate) fn push_metatable(&self) -> i32 {
-        self.table.push_table()
+    pub(crate) fn push_metatable(&self, L: lua_State) -> i32 {
+        self.table.push_table(L)
     }
 }
 
@@ -1,38 +1,86 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 
-use crate::lua::{luaL_ref, lua_State, lua_geti, LUA_REGISTRYINDEX, lua_gettop};
+use std::{cell::Cell, rc::Rc};
 
-#[derive(Debug, Clone)]
-pub struct LuaRef {
+use crate::{
+    lua::{
+        luaL_ref, luaL_unref, lua_Integer, lua_State, lua_checkstack, lua_gettop, lua_rawgeti, main_thread,
+        LUA_MINSTACK, LUA_REGISTRYINDEX,
+    },
+    state::open_flag,
+};
+
+/// A value kept alive in the registry.
+///
+/// It is bound to the main thread of the state, and not to the thread it was
+/// read from: that one may be a coroutine, collected long before the handle.
+/// Clones share the entry, which is released when the last one is dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaRef(Rc<Entry>);
+
+#[derive(Debug, PartialEq)]
+struct Entry {
     L: lua_State,
     id: i32,
+    /// Whether the state is still open, for entries this handle releases.
+    open: Option<Rc<Cell<bool>>>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        // Without the stack space, the entry is left behind rather than risk
+        // an error raised from a destructor.
+        if self.open.as_ref().is_some_and(|open| open.get()) && unsafe { lua_checkstack(self.L, 2) } != 0 {
+            unsafe { luaL_unref(self.L, LUA_REGISTRYINDEX, self.id) };
+        }
+    }
 }
 
 impl LuaRef {
 
     #[inline]
     pub fn register_last_stack_value(L: lua_State) -> Self {
-        Self {
-            L,
+        let main = main_thread(L);
+        let open = open_flag(L);
+        Self(Rc::new(Entry {
+            L: main,
             id: unsafe { luaL_ref(L, LUA_REGISTRYINDEX) },
-        }
+            open,
+        }))
     }
 
     #[inline]
     pub fn id(&self) -> i32{
-        self.id
+        self.0.id
     }
 
+    /// Pushes the value onto the stack of the main thread.
     #[inline]
     pub fn push_reference(&self) -> i32 {
-        unsafe { 
-            lua_geti(self.L, LUA_REGISTRYINDEX, self.id as i64);
-            lua_gettop(self.L)
+        // The main thread may be running a coroutine, from inside a callback
+        // with only its own stack space reserved.
+        unsafe { lua_checkstack(self.0.L, LUA_MINSTACK) };
+        self.push_onto(self.0.L)
+    }
+
+    /// Pushes the value onto the stack of `L`, any thread of the same state.
+    #[inline]
+    pub(crate) fn push_onto(&self, L: lua_State) -> i32 {
+        unsafe {
+            lua_rawgeti(L, LUA_REGISTRYINDEX, self.0.id as lua_Integer);
+            lua_gettop(L)
         }
     }
 
+    /// The main thread of the state.
+    #[inline]
+    pub(crate) fn L(&self) -> lua_State {
+        self.0.L
+    }
+
+    /// Handle on the existing entry `id`, which it never releases.
     pub(crate) fn from(L: lua_State, id : i32) -> Self{
-        Self { L, id }
+        Self(Rc::new(Entry { L: main_thread(L), id, open: None }))
     }
 }
@@ -1,8 +1,14 @@
 #![allow(non_snake_case)]
+use std::{cell::Cell, ffi::CStr, rc::Rc};
+
 use crate::lua::*;
 
+/// Registry key of the flag shared by [`State`] and the registry handles, which
+/// must not release their entry once the state is closed.
+const OPEN: &CStr = c"lunar.open";
+
 #[derive(Debug, Clone)]
-pub struct State(lua_State);
+pub struct State(lua_State, Rc<Cell<bool>>);
 
 impl State {
     pub(crate) fn new() -> State {
@@ -11,12 +17,19 @@ impl State {
             if ptr.is_null() {
                 panic!("[LUA]: Unable to create a lua state.")
             }
-            return State(ptr);
+            set_panic_handler(ptr);
+            #[cfg(any(feature = "lua51", feature = "luajit"))]
+            set_main_thread(ptr);
+            let open = Rc::new(Cell::new(true));
+            lua_pushlightuserdata(ptr, Rc::as_ptr(&open) as void_ptr);
+            lua_setfield(ptr, LUA_REGISTRYINDEX, OPEN.as_ptr() as const_char);
+            State(ptr, open)
         }
     }
 
     pub(super) fn destroy(&mut self) {
         unsafe {
+            self.1.set(false);
             lua_close(self.0);
             self.0 = std::ptr::null_mut();
         }
@@ -34,3 +47,19 @@ impl Drop for State {
         if !self.0.is_null() { self.destroy(); }
     }
 }
+
+/// The flag telling whether the state `L` belongs to is still open, if it was
+/// created by [`State::new`].
+pub(crate) fn open_flag(L: lua_State) -> Option<Rc<Cell<bool>>> {
+    unsafe {
+        lua_getfield(L, LUA_REGISTRYINDEX, OPEN.as_ptr() as const_char);
+        let flag = lua_touserdata(L, -1) as *const Cell<bool>;
+        lua_pop(L, 1);
+        if flag.is_null() {
+            return None;
+        }
+        // The state holds a strong count for as long as it is open.
+        Rc::increment_strong_count(flag);
+        Some(Rc::from_raw(flag))
+    }
+}
@@ -1,14 +1,18 @@
+#![allow(non_snake_case)]
+
 use std::rc::Rc;
 
 use crate::{
-    context::{LunarContext, Value},
+    context::{CallbackResult, LunarContext, Value},
+    convert::{mismatch, FromLua, IntoLua},
+    error::LunarError,
     lua::*,
     metatable::MetaTable,
     refr::LuaRef,
-    types::Type,
+    types::{LuaType, Type},
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Table {
     ctx: Rc<LunarContext>,
     luaref: LuaRef,
@@ -16,25 +20,29 @@ pub struct Table {
 
 impl Table {
     pub(crate) fn new(ctx: Rc<LunarContext>, name: &str, global: bool) -> Table {
+        assert_usable(ctx.L());
         unsafe {
             lua_createtable(ctx.L(), 0, 0);
             let mut table = lua_gettop(ctx.L());
 
             if !name.is_empty() {
-                lua_pushstring(ctx.L(), to_const_char(name.to_string()));
-                lua_setfield(ctx.L(), table, to_const_char("__name".to_string()));
+                lua_pushlstring(ctx.L(), name.as_ptr() as const_char, name.len());
+                ctx.set_field("__name", table);
 
                 if global {
+                    lua_pushglobaltable(ctx.L());
                     lua_pushvalue(ctx.L(), table);
-                    lua_setglobal(ctx.L(), to_const_char(name.to_string()));
+                    ctx.set_field(name, -2);
+                    ctx.pop_last();
                 }
             }
-            let luaref = LuaRef::register_last_stack_value(ctx.L());
+            // Never released: `Table::from` finds the entry again through `__ref`.
+            let luaref = LuaRef::from(ctx.L(), luaL_ref(ctx.L(), LUA_REGISTRYINDEX));
             luaref.push_reference();
             table = lua_gettop(ctx.L());
 
             ctx.push(Value::Int(luaref.id()));
-            lua_setfield(ctx.L(), table, to_const_char("__ref".to_string()));
+            ctx.set_field("__ref", table);
 
             ctx.pop_last();
             Table {
@@ -44,73 +52,142 @@ impl Table {
         }
     }
 
-    pub fn set(&self, field: &str, value: Value) {
+    /// Handle on the table at `idx`, kept alive through the registry.
+    pub(crate) fn from_stack(ctx: &LunarContext, idx: i32) -> Table {
+        unsafe { lua_pushvalue(ctx.L(), idx) };
+        let luaref = LuaRef::register_last_stack_value(ctx.L());
+        let ctx = Rc::new(LunarContext::new(luaref.L()));
+        Table { ctx, luaref }
+    }
+
+    /// Sets `field`, through the `__newindex` metamethod if there is one. An
+    /// error it raises is returned.
+    pub fn set<T: IntoLua>(&self, field: &str, value: T) -> Result<(), LunarError> {
+        self.set_with(field, |ctx| value.push_into(ctx))
+    }
+
+    /// Reads `field` as a `T`, through the `__index` metamethod if there is
+    /// one. An error it raises, or a value that does not convert, is returned.
+    pub fn get<T: FromLua>(&self, field: &str) -> Result<T, LunarError> {
+        self.get_with(field, |ctx| T::from_lua(ctx, -1))
+    }
+
+    fn get_with<T>(
+        &self,
+        field: &str,
+        read: impl FnOnce(&LunarContext) -> Result<T, LunarError>,
+    ) -> Result<T, LunarError> {
         let stack = self.luaref.push_reference();
-        self.ctx.push(value);
-        self.ctx.set_field(field, stack);
+        let value = self.ctx.get_field(field, stack).and_then(|_| {
+            let value = read(&self.ctx);
+            self.ctx.pop_last();
+            value
+        });
+        self.ctx.pop_last();
+        value
+    }
+
+    /// Reads an optional field: `None` if it is `nil`. Any other value must
+    /// convert to a `T`, or an error is returned as with [`get`](Table::get).
+    pub fn get_opt<T: FromLua>(&self, field: &str) -> Result<Option<T>, LunarError> {
+        self.get::<Option<T>>(field)
+    }
+
+    /// Same as [`get_opt`](Table::get_opt), with `default` for `nil`.
+    pub fn get_or<T: FromLua>(&self, field: &str, default: T) -> Result<T, LunarError> {
+        Ok(self.get_opt(field)?.unwrap_or(default))
+    }
+
+    /// Same as [`set`](Table::set) with a Rust callback. It may return a
+    /// `Result`, or nothing, see [`CallbackResult`].
+    pub fn set_function<R: CallbackResult>(
+        &self,
+        name: &str,
+        function: fn(ctx: LunarContext) -> R,
+    ) -> Result<(), LunarError> {
+        self.set_with(name, |ctx| push_function(ctx.L(), function))
+    }
+
+    fn set_with(&self, field: &str, push: impl FnOnce(&LunarContext)) -> Result<(), LunarError> {
+        usable(self.ctx.L())?;
+        let stack = self.luaref.push_reference();
+        push(&self.ctx);
+        let result = protected_setfield(self.ctx.L(), stack, field);
         self.ctx.pop_last();
+        result
     }
 
-    pub fn set_function(&self, name: &str, function: fn(ctx: LunarContext) -> i32) {
-        self.set(name, Value::Function(function));
+    /// Sets `field` of a table without metatable, which cannot fail.
+    pub(crate) fn init_field(&self, field: &str, push: impl FnOnce(&LunarContext)) {
+        let stack = self.luaref.push_reference();
+        push(&self.ctx);
+        self.ctx.set_field(field, stack);
+        self.ctx.pop_last();
     }
 
     pub fn set_metatable(&self, metatable: &MetaTable) {
         self.ctx.set_metatable(self, metatable);
     }
 
-    pub fn get_boolean(&self, field: &str) -> bool {
-        let stack = self.luaref.push_reference();
-        let field = self.ctx.get_field(field, stack);
-        self.ctx.get_boolean(field)
+    pub fn get_boolean(&self, field: &str) -> Result<bool, LunarError> {
+        self.get(field)
     }
 
-    pub fn get_int<T>(&self, field: &str) -> T
+    pub fn get_int<T>(&self, field: &str) -> Result<T, LunarError>
     where
         T: Type + From<i8> + From<i16> + From<i32>,
     {
-        let stack = self.luaref.push_reference();
-        let field = self.ctx.get_field(field, stack);
-        self.ctx.get_int::<T>(field)
+        self.get::<i32>(field).map(T::from)
     }
 
-    pub fn get_float<T>(&self, field: &str) -> T
+    pub fn get_float<T>(&self, field: &str) -> Result<T, LunarError>
     where
         T: Type + From<f64>,
     {
-        let stack = self.luaref.push_reference();
-        let field = self.ctx.get_field(field, stack);
-        self.ctx.get_float::<T>(field)
+        self.get::<f64>(field).map(T::from)
     }
 
-    pub fn get_uint(&self, field: &str) -> u32 {
-        let stack = self.luaref.push_reference();
-        let field = self.ctx.get_field(field, stack);
-        self.ctx.get_uint(field)
+    pub fn get_uint(&self, field: &str) -> Result<u32, LunarError> {
+        self.get(field)
     }
 
-    pub fn get_long(&self, field: &str) -> i64 {
-        let stack = self.luaref.push_reference();
-        let field = self.ctx.get_field(field, stack);
-        self.ctx.get_long(field)
+    pub fn get_long(&self, field: &str) -> Result<i64, LunarError> {
+        self.get(field)
     }
 
-    #[inline]
-    pub(crate) fn push_table(&self) -> i32 {
-        self.luaref.push_reference()
+    /// Reads a string field as raw bytes, embedded zeros and non-UTF-8 data
+    /// included.
+    pub fn get_bytes(&self, field: &str) -> Result<Vec<u8>, LunarError> {
+        self.get_with(field, |ctx| {
+            if ctx.get_type(-1) != LuaType::String {
+                return Err(mismatch(ctx, -1, "string"));
+            }
+            unsafe {
+                let mut len = 0;
+                let ptr = lua_tolstring(ctx.L(), -1, &mut len);
+                Ok(std::slice::from_raw_parts(ptr as *const u8, len).to_vec())
+            }
+        })
     }
 
-    pub fn from(ctx: Rc<LunarContext>, name: &str) -> Self {
-        unsafe {
-            ctx.get_global(name);
-            let table = lua_gettop(ctx.L());
-            ctx.get_field("__ref", table);
+    #[inline]
+    pub(crate) fn push_table(&self, L: lua_State) -> i32 {
+        self.luaref.push_onto(L)
+    }
 
-            let stack = lua_gettop(ctx.L());
-            let id = luaL_checkinteger(ctx.L(), stack) as i32;
-            let luaref = LuaRef::from(ctx.L(), id);
+    /// Handle on the global table `name` created with
+    /// [`create_table`](crate::lunar::Lunar::create_table). An error raised by a
+    /// metamethod, or a global that is not such a table, is returned.
+    pub fn from(ctx: Rc<LunarContext>, name: &str) -> Result<Self, LunarError> {
+        let table = ctx.get_global(name)?;
+        let id = ctx.get_field("__ref", table).and_then(|stack| {
+            let id = i32::from_lua(&ctx, stack);
+            ctx.pop_last();
+            id
+        });
+        ctx.pop_last();
+        let luaref = LuaRef::from(ctx.L(), id?);
 
-            Self { ctx, luaref }
-        }
+        Ok(Self { ctx, luaref })
     }
 }
@@ -1,13 +1,42 @@
 #[cfg(test)]
+#[allow(clippy::module_inception, clippy::assertions_on_constants)]
 mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::{BTreeMap, HashMap},
+        rc::Rc,
+    };
+
+    #[cfg(feature = "dynamic")]
+    use std::path::Path;
+
+    #[cfg(feature = "dynamic")]
+    use crate::{error::LibraryError, lua::check_library};
     use crate::{
         context::{Userdata, Value},
+        error::{ErrorValue, FrameKind, LunarError},
+        function::LuaFunction,
         lunar::Lunar,
+        table::Table,
+        types::{Number, NumberConversion},
     };
 
+    /// A new state. With the `dynamic` feature, it runs on the Lua library named
+    /// by `LUNAR_LUA_LIBRARY`, loaded by whichever test gets here first.
+    fn new_lunar() -> Lunar {
+        #[cfg(feature = "dynamic")]
+        {
+            let path = std::env::var("LUNAR_LUA_LIBRARY")
+                .expect("set LUNAR_LUA_LIBRARY to the Lua library to run the tests against");
+            Lunar::with_library(path).unwrap()
+        }
+        #[cfg(not(feature = "dynamic"))]
+        Lunar::new()
+    }
+
     #[test]
     fn lunar_state_is_valid() {
-        //let lunar = Lunar::new();
+        //let lunar = new_lunar();
         //assert!(!lunar.get().is_null());
     }
 
@@ -18,106 +47,586 @@ mod tests {
         // assert!(lunar.get().is_null());
     }
 
+    #[test]
+    fn lua_version_matches_feature() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        #[cfg(feature = "lua51")]
+        lunar.load("assert(_VERSION == 'Lua 5.1')").unwrap();
+        #[cfg(feature = "lua52")]
+        lunar.load("assert(_VERSION == 'Lua 5.2')").unwrap();
+        #[cfg(feature = "lua53")]
+        lunar.load("assert(_VERSION == 'Lua 5.3')").unwrap();
+        #[cfg(feature = "lua54")]
+        lunar.load("assert(_VERSION == 'Lua 5.4')").unwrap();
+        #[cfg(feature = "luajit")]
+        lunar.load("assert(_VERSION == 'Lua 5.1' and jit)").unwrap();
+        #[cfg(feature = "luau")]
+        lunar.load("assert(_VERSION == 'Luau')").unwrap();
+
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[cfg(feature = "luajit")]
+    #[test]
+    fn luajit_engine_mode() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        assert!(lunar.jit_off());
+        lunar.load("assert(not jit.status())").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        assert!(lunar.jit_on());
+        assert!(lunar.jit_flush());
+        lunar.load("assert(jit.status())").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[cfg(feature = "luajit")]
+    #[test]
+    fn luajit_long_beyond_32_bits() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("test", |ctx| {
+            assert_eq!(ctx.get_long(1), 1 << 40);
+            ctx.returns(Value::Long(-(1 << 40)))
+        }).unwrap();
+
+        lunar.load("assert(test(2^40) == -2^40)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[cfg(feature = "luau")]
+    #[test]
+    fn luau_sandbox_makes_globals_read_only() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.sandbox();
+
+        lunar.load("value = 10").unwrap();
+        assert!(lunar.exec().is_err());
+
+        lunar.load("math.sqrt = nil").unwrap();
+        assert!(lunar.exec().is_err());
+    }
+
+    #[cfg(feature = "luau")]
+    #[test]
+    fn luau_sandbox_thread_writable_globals() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("test", |ctx| ctx.returns(Value::Int(5))).unwrap();
+        lunar.sandbox();
+        lunar.sandbox_thread();
+
+        lunar.load("value = test() assert(value == 5 and math.sqrt(4) == 2)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        lunar.load("math.sqrt = nil").unwrap();
+        assert!(lunar.exec().is_err());
+    }
+
+    /// The library is process-wide and other tests may have loaded it already,
+    /// so the failure cases are checked without making it the loaded one.
+    #[cfg(feature = "dynamic")]
+    #[test]
+    fn with_library() {
+        let result = check_library(Path::new("/nonexistent/liblua.so"));
+        assert!(matches!(result, Err(LibraryError::Load { .. })));
+
+        #[cfg(target_os = "linux")]
+        assert!(matches!(
+            check_library(Path::new("libc.so.6")),
+            Err(LibraryError::MissingSymbol("luaL_newstate"))
+        ));
+
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("test", |ctx| ctx.returns(Value::Int(ctx.get_int::<i32>(1) * 2))).unwrap();
+
+        lunar.load("assert(test(21) == 42)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+        assert!(Lunar::new().load("return").is_ok());
+        assert!(Lunar::try_new().is_ok());
+        assert!(matches!(
+            Lunar::with_library("/nonexistent/liblua.so"),
+            Err(LunarError::Library(LibraryError::AlreadyLoaded(_)))
+        ));
+    }
+
     #[test]
     fn create_static_function_lua_test() {
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.create_static_function("test", |ctx| {
             assert!(true);
-            return ctx.returns(Value::Nil);
-        });
+            ctx.returns(Value::Nil)
+        }).unwrap();
 
-        lunar.load("test()");
+        lunar.load("test()").unwrap();
         lunar.exec().unwrap();
     }
 
     #[test]
     fn static_function_get_args_integer() {
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.create_static_function("test", |ctx| {
             assert_eq!(ctx.get_int::<i32>(1), 75);
             assert_eq!(ctx.get_int::<i32>(2), 1166);
 
-            return ctx.returns(Value::Nil);
-        });
+            ctx.returns(Value::Nil)
+        }).unwrap();
 
-        lunar.load("test(75, 1166)");
+        lunar.load("test(75, 1166)").unwrap();
         assert!(lunar.exec().is_ok())
     }
 
     #[test]
     fn static_function_get_args_string() {
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.create_static_function("test", |ctx| {
             assert_eq!(ctx.get_string(1), Ok("Hello World!".to_string()));
 
             ctx.returns(Value::Nil)
-        });
+        }).unwrap();
 
-        lunar.load("test('Hello World!')");
+        lunar.load("test('Hello World!')").unwrap();
         lunar.exec().unwrap();
     }
 
     #[test]
     fn static_function_return_nil() {
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.create_static_function("test", |ctx| {
-            return ctx.returns(Value::Nil);
+            ctx.returns(Value::Nil)
+        }).unwrap();
+
+        lunar.load("assert(test() == nil)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    fn load_syntax_error() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        let error = lunar.load("local x = 1\nlocal = 2").unwrap_err();
+        assert!(matches!(error, LunarError::Syntax { .. }));
+        assert_eq!(error.line(), Some(2));
+        assert_eq!(lunar.context().stack_size(), 0);
+
+        // Lua 5.1 and Luau accept a NUL byte in the source, the others reject it.
+        let result = lunar.load("x = 1\0");
+        if cfg!(any(feature = "lua51", feature = "luau")) {
+            assert_eq!(result, Ok(()));
+            assert_eq!(lunar.exec(), Ok(()));
+        } else {
+            assert!(matches!(result, Err(LunarError::Syntax { .. })), "{result:?}");
+        }
+        assert_eq!(lunar.context().stack_size(), 0);
+
+        lunar.load("assert(true)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+        assert_eq!(lunar.context().stack_size(), 0);
+    }
+
+    #[test]
+    fn exec_without_chunk() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        assert!(lunar.load("local = ").is_err());
+        match lunar.exec() {
+            Err(LunarError::Runtime { message, .. }) => assert_eq!(message, "no chunk loaded"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        lunar.load("x = 1").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+        assert!(lunar.exec().is_err());
+        assert_eq!(lunar.context().stack_size(), 0);
+    }
+
+    #[test]
+    fn exec_runtime_error() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.load("local x = 1\nerror('boom')").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert!(matches!(error, LunarError::Runtime { .. }));
+        assert_eq!(error.line(), Some(2));
+        assert!(error.chunk().is_some());
+        assert!(error.to_string().ends_with(":2: boom"));
+    }
+
+    #[test]
+    fn exec_runtime_error_without_location() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.load("error('boom', 0)").unwrap();
+        match lunar.exec() {
+            Err(LunarError::Runtime { chunk, line, message, .. }) => {
+                assert_eq!((chunk, line), (None, None));
+                assert_eq!(message, "boom");
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn exec_traceback() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("fail", |ctx| -> i32 { ctx.error("invalid input") }).unwrap();
+
+        lunar.load("local function inner()\n  fail()\nend\ninner()").unwrap();
+        let error = lunar.exec().unwrap_err();
+        let traceback = error.traceback();
+
+        assert_eq!(traceback[0].kind, FrameKind::Rust);
+        assert_eq!(traceback[1].kind, FrameKind::Lua);
+        assert_eq!(traceback[1].line, Some(2));
+        assert_eq!(traceback[1].name.as_deref(), Some("inner"));
+        assert_eq!(traceback[2].line, Some(4));
+        assert!(format!("{error:#}").contains("\nstack traceback:\n"));
+
+        lunar.load("error('boom')").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert_eq!(error.traceback()[0].kind, FrameKind::C);
+        assert_eq!(error.traceback()[1].kind, FrameKind::Lua);
+    }
+
+    #[test]
+    fn custom_message_handler() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.set_message_handler(|ctx| {
+            let frames = ctx.traceback().len();
+            ctx.returns(Value::String(format!("handled with {frames} frames")))
         });
 
-        lunar.load("assert(test() == nil)");
+        lunar.load("error('boom')").unwrap();
+        match lunar.exec() {
+            Err(LunarError::Runtime { message, traceback, .. }) => {
+                assert!(message.starts_with("handled with"));
+                assert!(traceback.is_empty());
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn exec_table_error() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.load("error({code = 404})").unwrap();
+        match lunar.exec() {
+            Err(LunarError::Value { value: Value::Table(table), snapshot, message, traceback }) => {
+                assert_eq!(table.get::<i32>("code"), Ok(404));
+                assert!(matches!(
+                    snapshot.get("code"),
+                    Some(ErrorValue::Number(Number::Integer(404))) | Some(ErrorValue::Number(Number::Float(404.0)))
+                ));
+                assert_eq!(message, "(error object is a table value)");
+                assert!(!traceback.is_empty());
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        lunar.load("local e = {inner = {}} e.inner.outer = e error(e)").unwrap();
+        let error = lunar.exec().unwrap_err();
+        drop(lunar);
+        let std_error: Box<dyn std::error::Error> = Box::new(error.clone());
+        assert_eq!(std_error.to_string(), "(error object is a table value)");
+        match error {
+            LunarError::Value { snapshot, .. } => {
+                let snapshot = std::thread::spawn(move || snapshot).join().unwrap();
+                let inner = snapshot.get("inner").unwrap();
+                assert!(matches!(inner.get("outer"), Some(ErrorValue::Table(_))));
+            }
+            error => panic!("unexpected error: {error:?}"),
+        }
+    }
+
+    #[test]
+    fn exec_table_error_tostring() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.load("
+            local mt = {__tostring = function(e) return 'not found: ' .. e.path end}
+            error(setmetatable({path = '/index'}, mt))
+        ").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert!(matches!(&error, LunarError::Value { value: Value::Table(_), snapshot, .. } if snapshot.get("path") == Some(&ErrorValue::String("/index".into()))));
+        assert_eq!(error.to_string(), "not found: /index");
+
+        lunar.load("error(setmetatable({}, {__tostring = function() error('oops') end}))").unwrap();
+        assert_eq!(lunar.exec().unwrap_err().to_string(), "(error object is a table value)");
+    }
+
+    #[test]
+    fn exec_boolean_error() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.load("error(false)").unwrap();
+        match lunar.exec() {
+            Err(LunarError::Value { value, snapshot, message, .. }) => {
+                assert_eq!(value, Value::Bool(false));
+                assert_eq!(snapshot, ErrorValue::Bool(false));
+                assert_eq!(message, "(error object is a boolean value)");
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn exec_type_mismatch() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("test", |ctx| ctx.returns(Value::Int(ctx.get_int::<i32>(1)))).unwrap();
+
+        lunar.load("test('x')").unwrap();
+        match lunar.exec() {
+            Err(LunarError::TypeMismatch { position, expected, found, .. }) => {
+                assert_eq!(position, 1);
+                assert_eq!(expected, "int");
+                assert_eq!(found, "string");
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn callback_error() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("fail", |ctx| -> i32 { ctx.error("invalid input") }).unwrap();
+
+        lunar.load("\nfail()").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert!(matches!(&error, LunarError::Callback { message, .. } if message == "invalid input"));
+        assert_eq!(error.line(), Some(2));
+
+        lunar.load("local ok, e = pcall(fail)\nassert(not ok and tostring(e) == 'invalid input')").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    fn callback_panic() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("explode", |_| -> i32 { panic!("kaboom") }).unwrap();
+
+        lunar.load("\nexplode()").unwrap();
+        match lunar.exec() {
+            Err(LunarError::CallbackPanic { message, line, .. }) => {
+                assert_eq!(message, "kaboom");
+                assert_eq!(line, Some(2));
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        lunar.load("local ok, e = pcall(explode)\nassert(not ok and tostring(e) == 'callback panicked: kaboom')").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct NotFound(String);
+
+    impl std::fmt::Display for NotFound {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "'{}' not found", self.0)
+        }
+    }
+
+    impl std::error::Error for NotFound {}
+
+    #[test]
+    fn callback_returns_error() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("find", |ctx| {
+            let name = ctx.get_string(1)?;
+            match name.as_str() {
+                "answer" => Ok(ctx.returns(Value::Int(42))),
+                _ => Err(LunarError::external(NotFound(name))),
+            }
+        }).unwrap();
+
+        lunar.load("assert(find('answer') == 42)\nlocal ok, e = pcall(find, 'x')\nassert(not ok and tostring(e) == \"'x' not found\")").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        lunar.load("find('y')").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert_eq!(error.downcast_ref::<NotFound>(), Some(&NotFound("y".to_string())));
+        assert_eq!(error.to_string(), "'y' not found");
+
+        lunar.create_static_function("nothing", |_| ()).unwrap();
+        lunar.load("assert(select('#', nothing()) == 0)").unwrap();
         assert_eq!(lunar.exec(), Ok(()));
     }
 
+    #[test]
+    fn callback_int_out_of_range() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("test", |ctx| ctx.returns(Value::Uint(ctx.get_uint::<u32>(1)))).unwrap();
+
+        lunar.load("test(-1)").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert!(error.to_string().contains("argument #1"));
+        assert!(error.to_string().contains("value out of range for u32"));
+    }
+
     #[test]
     fn create_table_empty() {
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.create_table("Table", true, |_table| {});
 
-        lunar.load("assert(Table)");
+        lunar.load("assert(Table)").unwrap();
         assert_eq!(lunar.exec(), Ok(()));
     }
 
     #[test]
     fn create_table_field_int() {
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.create_table("Table", true, |table| {
-            table.set("value", Value::Int(25));
+            table.set("value", Value::Int(25)).unwrap();
         });
 
-        lunar.load("assert(Table.value == 25)");
+        lunar.load("assert(Table.value == 25)").unwrap();
         assert_eq!(lunar.exec(), Ok(()));
     }
 
     #[test]
     fn create_table_field_string() {
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.create_table("Table", true, |table| {
-            table.set("value", Value::String(String::from("Hello Lunar!")));
+            table.set("value", Value::String(String::from("Hello Lunar!"))).unwrap();
         });
 
-        lunar.load("assert(Table.value == 'Hello Lunar!')");
+        lunar.load("assert(Table.value == 'Hello Lunar!')").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    fn read_only_config(lunar: &Lunar) -> Table {
+        lunar.create_table("config", true, |_| {});
+        lunar.load(
+            "
+            setmetatable(config, {
+                __index = function(t, k) error('no such key: ' .. k) end,
+                __newindex = function(t, k) error('config is read-only: ' .. k) end,
+            })
+            ",
+        ).unwrap();
+        lunar.exec().unwrap();
+        Table::from(lunar.context(), "config").unwrap()
+    }
+
+    #[test]
+    fn table_set_newindex_error() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        let table = read_only_config(&lunar);
+        let top = lunar.context().stack_size();
+        match table.set("debug", Value::Bool(true)) {
+            Err(LunarError::Runtime { message, .. }) => assert!(message.ends_with("config is read-only: debug")),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        assert!(table.set_function("reload", |_| 0).is_err());
+        assert_eq!(lunar.context().stack_size(), top);
+
+        lunar.load("assert(rawget(config, 'debug') == nil)").unwrap();
         assert_eq!(lunar.exec(), Ok(()));
     }
 
+    #[test]
+    fn create_global_value_error() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.load("setmetatable(_G, { __newindex = function(t, k) error('no new globals: ' .. k) end })").unwrap();
+        lunar.exec().unwrap();
+
+        let error = lunar.create_global_value("x", Value::Int(1)).unwrap_err();
+        assert!(error.to_string().ends_with("no new globals: x"));
+        let error = lunar.create_static_function("f", |ctx| ctx.returns(())).unwrap_err();
+        assert!(error.to_string().ends_with("no new globals: f"));
+        let error = lunar.register("g", |a: i64| a).unwrap_err();
+        assert!(error.to_string().ends_with("no new globals: g"));
+        assert!(Table::from(lunar.context(), "missing").is_err());
+        assert_eq!(lunar.context().stack_size(), 0);
+    }
+
+    /// LuaJIT is built without unwind tables, a panic cannot cross its frames.
+    #[cfg(not(feature = "luajit"))]
+    #[test]
+    fn unprotected_error_panics() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        let table = read_only_config(&lunar);
+        let error = table.get_long("missing").unwrap_err();
+        assert!(error.to_string().ends_with("no such key: missing"), "{error}");
+
+        let ctx = lunar.context();
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let stack = table.push_table(ctx.L());
+            ctx.push(Value::Int(1));
+            ctx.set_field("debug", stack);
+        })).unwrap_err();
+        let message = payload.downcast::<String>().unwrap();
+        assert!(message.contains("config is read-only: debug"), "{message}");
+
+        match lunar.load("x = 1") {
+            Err(LunarError::Runtime { message, .. }) => assert_eq!(message, "state poisoned by an unprotected error"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        assert!(lunar.get_global::<i64>("x").is_err());
+        assert!(lunar.create_global_value("y", 1).is_err());
+        assert!(table.set("debug", true).is_err());
+
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lunar.create_table("t", true, |_| {});
+        })).unwrap_err();
+        let message = payload.downcast::<String>().unwrap();
+        assert_eq!(*message, "state poisoned by an unprotected error");
+    }
+
     #[test]
     fn create_userdata() {
+        #[allow(dead_code)]
         struct Calculator(i32, i32);
 
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.register_userdata("Calculator", |methods| {
@@ -132,16 +641,17 @@ mod tests {
             local calc = Calculator()
             assert(calc)
             ",
-        );
+        ).unwrap();
 
         assert_eq!(lunar.exec(), Ok(()));
     }
 
     #[test]
     fn create_userdata_get_args() {
+        #[allow(dead_code)]
         struct Calculator(i32, i32);
 
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.register_userdata("Calculator", |methods| {
@@ -158,7 +668,7 @@ mod tests {
             local calc = Calculator(10, 10)
             assert(calc)
             ",
-        );
+        ).unwrap();
 
         assert_eq!(lunar.exec(), Ok(()));
     }
@@ -168,7 +678,7 @@ mod tests {
         #[derive(Debug)]
         struct Calculator(i32, i32);
 
-        let lunar = Lunar::new();
+        let lunar = new_lunar();
         lunar.load_std_library();
 
         lunar.register_userdata("Calculator", |methods| {
@@ -192,8 +702,751 @@ mod tests {
             local value = calc:sun()
             assert(value == 20)
             ",
+        ).unwrap();
+
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    fn convert_globals() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_global_value("count", 42u8).unwrap();
+        lunar.create_global_value("big", u64::MAX).unwrap();
+        lunar.create_global_value("ratio", 0.5f32).unwrap();
+        lunar.create_global_value("name", "lunar").unwrap();
+        lunar.create_global_value("missing", None::<i32>).unwrap();
+        lunar.create_global_value("list", vec![1, 2, 3]).unwrap();
+        lunar.create_global_value("ports", HashMap::from([("http".to_string(), 80)])).unwrap();
+        lunar.create_global_value("names", BTreeMap::from([(1, "one"), (2, "two")])).unwrap();
+
+        lunar.load(
+            "
+            assert(count == 42 and big == 2^64 and ratio == 0.5 and name == 'lunar')
+            assert(missing == nil and #list == 3 and list[3] == 3)
+            assert(ports.http == 80 and names[2] == 'two')
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        assert_eq!(lunar.get_global::<i32>("count"), Ok(42));
+        assert_eq!(lunar.get_global::<f32>("ratio"), Ok(0.5));
+        assert_eq!(lunar.get_global::<String>("name"), Ok("lunar".to_string()));
+        assert_eq!(lunar.get_global::<Option<bool>>("missing"), Ok(None));
+        assert_eq!(lunar.get_global::<Vec<i64>>("list"), Ok(vec![1, 2, 3]));
+        assert_eq!(lunar.get_global::<HashMap<String, u16>>("ports"), Ok(HashMap::from([("http".to_string(), 80)])));
+        assert_eq!(
+            lunar.get_global::<BTreeMap<i32, String>>("names"),
+            Ok(BTreeMap::from([(1, "one".to_string()), (2, "two".to_string())]))
+        );
+        assert_eq!(lunar.get_global::<Value>("name"), Ok(Value::String("lunar".to_string())));
+    }
+
+    #[test]
+    fn convert_errors() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.load("name, half, big, list = 'lunar', 1.5, 300, {1, 'two'}").unwrap();
+        lunar.exec().unwrap();
+
+        let error = lunar.get_global::<i32>("name").unwrap_err();
+        assert_eq!(error.to_string(), "integer expected, got string");
+        assert_eq!(
+            lunar.get_global::<i32>("half").unwrap_err().to_string(),
+            "number has no integer representation"
+        );
+        assert_eq!(lunar.get_global::<u8>("big").unwrap_err().to_string(), "value out of range for u8");
+        assert_eq!(
+            lunar.get_global::<Vec<i32>>("list").unwrap_err().to_string(),
+            "index 2: integer expected, got string"
+        );
+        assert!(lunar.get_global::<bool>("nothing").is_err());
+        assert_eq!(lunar.context().stack_size(), 0);
+    }
+
+    #[test]
+    fn convert_arguments() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("divmod", |ctx| {
+            let a: i64 = ctx.get(1);
+            let b: i64 = ctx.get(2);
+            ctx.returns((a / b, a % b))
+        }).unwrap();
+        lunar.create_static_function("join", |ctx| {
+            let parts: Vec<String> = ctx.get(1);
+            let sep: Option<String> = ctx.get(2);
+            ctx.returns(parts.join(&sep.unwrap_or_default()))
+        }).unwrap();
+
+        lunar.load(
+            "
+            local q, r = divmod(7, 2)
+            assert(q == 3 and r == 1)
+            assert(join({'a', 'b'}, '-') == 'a-b' and join({'a', 'b'}) == 'ab')
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        lunar.load("\ndivmod('7', 2)").unwrap();
+        match lunar.exec() {
+            Err(LunarError::TypeMismatch { position, expected, found, line, .. }) => {
+                assert_eq!((position, line), (1, Some(2)));
+                assert_eq!((expected.as_str(), found.as_str()), ("integer", "string"));
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        lunar.load("divmod(7.5, 2)").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert!(error.to_string().contains("argument #1"));
+        assert!(error.to_string().ends_with("(number has no integer representation)"));
+    }
+
+    #[test]
+    fn table_get_set() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.create_table("config", true, |_| {});
+        let top = lunar.context().stack_size();
+        let config = Table::from(lunar.context(), "config").unwrap();
+        assert_eq!(lunar.context().stack_size(), top);
+
+        config.set("retries", 3).unwrap();
+        config.set("hosts", vec!["a", "b"]).unwrap();
+        assert_eq!(config.get::<u32>("retries"), Ok(3));
+        assert_eq!(config.get::<Vec<String>>("hosts"), Ok(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(config.get::<Option<String>>("user"), Ok(None));
+
+        let hosts: Table = config.get("hosts").unwrap();
+        hosts.set("primary", "a").unwrap();
+        assert_eq!(config.get::<Table>("hosts").unwrap().get::<String>("primary"), Ok("a".to_string()));
+        assert!(config.get::<Table>("retries").is_err());
+    }
+
+    #[test]
+    fn get_value_any_type() {
+        #[allow(dead_code)]
+        struct Point(i32, i32);
+
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.register_userdata("Point", |methods| {
+            methods.constructor(|ctx| {
+                let point = Userdata::new(Point(ctx.get(2), ctx.get(3)));
+                ctx.returns(Value::Userdata("Point", point.as_ptr(), point.size()))
+            });
+        });
+
+        lunar.load(
+            "
+            values = {42, 1.5, 'moon', true, {}, print, Point(1, 2), coroutine.create(function() end)}
+            ",
+        ).unwrap();
+        lunar.exec().unwrap();
+
+        let values: Vec<Value> = lunar.get_global("values").unwrap();
+        #[cfg(any(feature = "lua53", feature = "lua54"))]
+        assert_eq!(values[0], Value::Long(42));
+        #[cfg(not(any(feature = "lua53", feature = "lua54")))]
+        assert_eq!(values[0], Value::Float(42.0));
+        assert_eq!(values[1..4], [Value::Float(1.5), Value::String("moon".to_string()), Value::Bool(true)]);
+        assert!(matches!(values[4], Value::Table(_)));
+        assert!(matches!(values[5], Value::LuaFunction(_)));
+        match &values[6] {
+            Value::AnyUserdata(userdata) => assert_eq!(userdata.name().as_deref(), Some("Point")),
+            value => panic!("unexpected value: {value:?}"),
+        }
+        assert!(matches!(values[7], Value::Thread(_)));
+        assert_eq!(lunar.get_global::<Value>("nothing"), Ok(Value::Nil));
+
+        lunar.create_global_value("copies", values).unwrap();
+        lunar.load(
+            "
+            for i = 1, #values do assert(rawequal(copies[i], values[i])) end
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    fn returns_many_values() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("lookup", |ctx| {
+            let key: String = ctx.get(1);
+            match key.as_str() {
+                "answer" => ctx.returns((42.5, None::<String>)),
+                _ => ctx.returns((None::<f64>, format!("no such key: {key}"))),
+            }
+        }).unwrap();
+        lunar.create_static_function("range", |ctx| {
+            let n: i64 = ctx.get(1);
+            ctx.returns_many((1..=n).map(Value::Long))
+        }).unwrap();
+        lunar.create_static_function("padded", |ctx| {
+            ctx.returns_many([Value::Int(1), Value::Nil, Value::Nil])
+        }).unwrap();
+        lunar.create_static_function("nothing", |ctx| ctx.returns(())).unwrap();
+
+        lunar.load(
+            "
+            local v, err = lookup('answer')
+            assert(v == 42.5 and err == nil and select('#', lookup('answer')) == 2)
+            v, err = lookup('tau')
+            assert(v == nil and err == 'no such key: tau')
+            assert(select('#', range(100)) == 100 and select(100, range(100)) == 100)
+            assert(select('#', padded()) == 3 and select('#', nothing()) == 0)
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    fn variadic_args() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("format", |ctx| {
+            let args = ctx.args();
+            let pattern: String = args.get(1);
+            let mut values = args.rest(2).iter();
+            let mut parts = pattern.split("{}");
+            let mut text = parts.next().unwrap_or_default().to_string();
+            for part in parts {
+                match values.next() {
+                    Some(Value::String(s)) => text.push_str(&s),
+                    value => text.push_str(&format!("{value:?}")),
+                }
+                text.push_str(part);
+            }
+            ctx.returns(text)
+        }).unwrap();
+        lunar.create_static_function("count", |ctx| {
+            let args = ctx.args();
+            let rest = args.rest(2);
+            ctx.returns((args.len() as i32, rest.len() as i32, args.is_none(3), rest.rest(10).is_empty()))
+        }).unwrap();
+        lunar.create_static_function("sum", |ctx| {
+            let args = ctx.args();
+            let total: f64 = (1..=args.len()).map(|i| args.get::<f64>(i)).sum();
+            ctx.returns(total)
+        }).unwrap();
+
+        lunar.load(
+            "
+            assert(format('{} + {} = {}', 'one', 'two', 'three') == 'one + two = three')
+            local n, rest, absent, empty = count(1, nil, nil)
+            assert(n == 3 and rest == 2 and absent == false and empty == true)
+            n, rest, absent = count(1)
+            assert(n == 1 and rest == 0 and absent == true)
+            assert(sum() == 0 and sum(1, 2, 3.5) == 6.5)
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        lunar.load("sum(1, 2, 'x')").unwrap();
+        match lunar.exec() {
+            Err(LunarError::TypeMismatch { position, .. }) => assert_eq!(position, 3),
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        lunar.create_static_function("first", |ctx| ctx.returns(ctx.args().get::<Value>(0))).unwrap();
+        lunar.load("first(1)").unwrap();
+        let message = lunar.exec().unwrap_err().to_string();
+        assert!(message.contains("argument #0") && message.ends_with("(argument positions start at 1)"), "{message}");
+
+        lunar.create_static_function("late", |ctx| {
+            let args = ctx.args();
+            let pushed = ctx.returns("pushed");
+            let second: Option<String> = args.get(2);
+            let third: i64 = args.rest(2).get(2);
+            pushed + ctx.returns((second, third))
+        }).unwrap();
+        lunar.load("late(1)").unwrap();
+        let error = lunar.exec().unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("bad argument #3") && message.ends_with("got no value)"), "{message}");
+        lunar.load("local _, second, third = late(1, nil, 3) assert(second == nil and third == 3)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        let packet = vec![0x00, 0xff, b'L', 0x00, 0x80];
+        lunar.create_global_value("packet", Value::Bytes(packet.clone())).unwrap();
+        lunar.create_global_value("label", Value::String("a\0b".to_string())).unwrap();
+        lunar.create_static_function("checksum", |ctx| {
+            let bytes = ctx.get_bytes(1);
+            ctx.returns((bytes.len() as i32, bytes.iter().map(|&b| b as i32).sum::<i32>()))
+        }).unwrap();
+
+        lunar.load(
+            "
+            assert(#packet == 5 and packet:byte(2) == 255 and #label == 3)
+            local len, sum = checksum(packet)
+            assert(len == 5 and sum == 0xff + 76 + 0x80)
+            frame = {payload = packet .. '\\0'}
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        let mut frame = packet.clone();
+        frame.push(0);
+        assert_eq!(lunar.get_global::<Value>("packet"), Ok(Value::Bytes(packet)));
+        assert_eq!(lunar.get_global::<Value>("label"), Ok(Value::String("a\0b".to_string())));
+        let frame_table: Table = lunar.get_global("frame").unwrap();
+        assert_eq!(frame_table.get_bytes("payload"), Ok(frame));
+
+        frame_table.set("a\0b", 1).unwrap();
+        lunar.create_global_value("x\0y", 2).unwrap();
+        lunar.create_table("name\0space", true, |_| {});
+        lunar.load("assert(frame['a\\0b'] == 1 and frame.a == nil and _G['x\\0y'] == 2 and x == nil)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+        assert_eq!(frame_table.get::<i64>("a\0b"), Ok(1));
+        assert_eq!(lunar.get_global::<i64>("x\0y"), Ok(2));
+        assert!(lunar.get_global::<Table>("name\0space").is_ok());
+    }
+
+    #[test]
+    fn number_subtypes() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("subtype", |ctx| {
+            let integer = ctx.is_integer(1);
+            match ctx.get_number(1) {
+                Number::Integer(n) => ctx.returns(("integer", n, integer)),
+                Number::Float(n) => ctx.returns(("float", n, integer)),
+            }
+        }).unwrap();
+
+        #[cfg(any(feature = "lua53", feature = "lua54"))]
+        lunar.load("assert(subtype(3) == 'integer' and select(3, subtype(3)) and subtype(3.0) == 'float')").unwrap();
+        #[cfg(not(any(feature = "lua53", feature = "lua54")))]
+        lunar.load("assert(subtype(3) == 'float' and not select(3, subtype(3)))").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        lunar.load("two, half, three = 2.0, 2.5, 3").unwrap();
+        lunar.exec().unwrap();
+        assert_eq!(lunar.get_global::<Number>("half"), Ok(Number::Float(2.5)));
+
+        assert_eq!(lunar.get_global::<i32>("two"), Ok(2));
+        assert!(lunar.get_global::<i32>("half").is_err());
+        assert_eq!(lunar.get_global::<f64>("three"), Ok(3.0));
+
+        lunar.set_number_conversion(NumberConversion::Truncating);
+        assert_eq!(lunar.get_global::<i32>("half"), Ok(2));
+
+        lunar.set_number_conversion(NumberConversion::Strict);
+        assert!(lunar.get_global::<i32>("half").is_err());
+        #[cfg(any(feature = "lua53", feature = "lua54"))]
+        {
+            assert_eq!(lunar.get_global::<i32>("two").unwrap_err().to_string(), "number is not an integer");
+            assert_eq!(lunar.get_global::<f64>("three").unwrap_err().to_string(), "number is not a float");
+            assert_eq!(lunar.get_global::<Number>("three"), Ok(Number::Integer(3)));
+        }
+        #[cfg(not(any(feature = "lua53", feature = "lua54")))]
+        assert_eq!(lunar.get_global::<i32>("two"), Ok(2));
+    }
+
+    #[cfg(any(feature = "lua53", feature = "lua54"))]
+    #[test]
+    fn lossless_float_from_integer() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.load("exact, inexact = 1 << 53, math.maxinteger").unwrap();
+        lunar.exec().unwrap();
+
+        assert_eq!(lunar.get_global::<f64>("exact"), Ok(9007199254740992.0));
+        assert_eq!(
+            lunar.get_global::<f64>("inexact").unwrap_err().to_string(),
+            "integer has no exact float representation"
         );
+        lunar.set_number_conversion(NumberConversion::Truncating);
+        assert_eq!(lunar.get_global::<f64>("inexact"), Ok(i64::MAX as f64));
+    }
+
+    #[test]
+    fn numeric_ranges() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("to_i32", |ctx| ctx.returns(Value::Int(ctx.get_int::<i32>(1)))).unwrap();
+        lunar.create_static_function("to_f32", |ctx| ctx.returns(ctx.get::<f32>(1))).unwrap();
+        lunar.create_static_function("to_usize", |ctx| ctx.returns(ctx.get::<usize>(1))).unwrap();
+
+        lunar.load("assert(to_i32(-2^31) == -2^31 and to_f32(0.5) == 0.5 and to_f32(1/0) == 1/0 and to_usize(7) == 7)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        for (call, ty) in [("to_i32(2^40)", "i32"), ("to_f32(1e300)", "f32"), ("to_usize(-1)", "usize")] {
+            lunar.load(call).unwrap();
+            let error = lunar.exec().unwrap_err().to_string();
+            assert!(error.contains("argument #1"), "{error}");
+            #[cfg(not(feature = "luau"))]
+            assert!(error.contains(&format!("to '{}'", call.split('(').next().unwrap())), "{error}");
+            assert!(error.ends_with(&format!("(value out of range for {ty})")), "{error}");
+        }
+
+        lunar.create_global_value("wide", 1i128 << 70).unwrap();
+        lunar.create_global_value("max", u64::MAX).unwrap();
+        lunar.load("assert(wide == 2^70) negative = -1").unwrap();
+        lunar.exec().unwrap();
+        assert_eq!(lunar.get_global::<i128>("wide"), Ok(1 << 70));
+        assert_eq!(lunar.get_global::<u128>("max"), Ok(1 << 64));
+        assert_eq!(lunar.get_global::<u64>("max").unwrap_err().to_string(), "value out of range for u64");
+        assert_eq!(lunar.get_global::<u64>("negative").unwrap_err().to_string(), "value out of range for u64");
+        assert_eq!(lunar.get_global::<i128>("negative"), Ok(-1));
+    }
+
+    #[test]
+    fn optional_arguments() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.create_static_function("repeat_text", |ctx| {
+            let text: String = ctx.get(1);
+            let times = ctx.get_or(2, 2usize);
+            let separator = ctx.get_opt::<String>(3);
+            ctx.returns(vec![text; times].join(separator.as_deref().unwrap_or("")))
+        }).unwrap();
+
+        lunar.load(
+            "
+            assert(repeat_text('a') == 'aa' and repeat_text('a', nil) == 'aa')
+            assert(repeat_text('a', 3, ',') == 'a,a,a' and repeat_text('a', nil, '-') == 'a-a')
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        lunar.load("repeat_text('a', 'three')").unwrap();
+        match lunar.exec() {
+            Err(LunarError::TypeMismatch { position, expected, found, .. }) => {
+                assert_eq!(position, 2);
+                assert_eq!((expected.as_str(), found.as_str()), ("integer", "string"));
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        lunar.load("settings = {port = 8080, host = false}").unwrap();
+        lunar.exec().unwrap();
+        let settings: Table = lunar.get_global("settings").unwrap();
+        assert_eq!(settings.get_or("port", 80), Ok(8080));
+        assert_eq!(settings.get_or("timeout", 30.0), Ok(30.0));
+        assert_eq!(settings.get_opt::<String>("user"), Ok(None));
+        assert!(settings.get_or("host", "localhost".to_string()).is_err());
+
+        #[cfg(feature = "luau")]
+        {
+            lunar.load("data = buffer.create(4)").unwrap();
+            lunar.exec().unwrap();
+            assert!(lunar.get_global::<Option<i64>>("data").is_err());
+        }
+    }
+
+    #[test]
+    fn closures_capture_state() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        let greeting = String::from("hello");
+        let greet = lunar.create_function(move |ctx| {
+            let name: String = ctx.get(1);
+            ctx.returns(format!("{greeting} {name}"))
+        });
+        lunar.create_global_value("greet", greet).unwrap();
+
+        let mut count = 0;
+        let counter = lunar.create_function_mut(move |ctx| {
+            count += 1;
+            ctx.returns(count)
+        });
+        lunar.load("counters = {}").unwrap();
+        lunar.exec().unwrap();
+        let counters: Table = lunar.get_global("counters").unwrap();
+        counters.set("next", counter).unwrap();
+
+        lunar.load(
+            "
+            assert(greet('lua') == 'hello lua')
+            assert(counters.next() == 1 and counters.next() == 2)
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        let recurse = lunar.create_function_mut(|ctx| {
+            ctx.call_function(1, vec![Value::Nil], 0)?;
+            Ok::<_, LunarError>(ctx.returns(()))
+        });
+        lunar.create_global_value("recurse", recurse).unwrap();
+        lunar.load("recurse(recurse)").unwrap();
+        match lunar.exec() {
+            Err(LunarError::Callback { message, .. }) => assert_eq!(message, "mutable closure called recursively"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        let mut total = 0;
+        let add = lunar.create_function_mut(move |ctx| {
+            total += ctx.get::<i64>(1);
+            ctx.returns(total)
+        });
+        lunar.create_global_value("add", add).unwrap();
+        lunar.load("assert(not pcall(add, 'x'))\nassert(add(1) == 1 and add(2) == 3)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    fn closures_dropped_with_state() {
+        let state = Rc::new(());
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        let captured = Rc::clone(&state);
+        let function = lunar.create_function(move |ctx| ctx.returns(Rc::strong_count(&captured) as i64));
+        lunar.create_global_value("strong_count", function).unwrap();
+        lunar.load("assert(strong_count() == 2)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+        assert_eq!(Rc::strong_count(&state), 2);
+
+        drop(lunar);
+        assert_eq!(Rc::strong_count(&state), 1);
+    }
+
+    #[test]
+    fn register_typed_functions() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        lunar.register("add", |a: i32, b: i32| a + b).unwrap();
+        lunar.register("greet", |name: String, greeting: Option<String>| {
+            format!("{}, {name}!", greeting.as_deref().unwrap_or("Hello"))
+        }).unwrap();
+        lunar.register("divmod", |a: i64, b: i64| (a / b, a % b)).unwrap();
+        lunar.register("checked_div", |a: i64, b: i64| {
+            a.checked_div(b).ok_or_else(|| LunarError::external(std::fmt::Error))
+        }).unwrap();
+
+        lunar.load(
+            "
+            assert(add(2, 3) == 5)
+            assert(greet('Lua') == 'Hello, Lua!' and greet('Lua', 'Hi') == 'Hi, Lua!')
+            local q, r = divmod(7, 2)
+            assert(q == 3 and r == 1)
+            assert(checked_div(6, 3) == 2 and not pcall(checked_div, 1, 0))
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        lunar.load("add(1, 'two')").unwrap();
+        match lunar.exec() {
+            Err(LunarError::TypeMismatch { position, function, expected, found, line, .. }) => {
+                assert_eq!(position, 2);
+                assert_eq!(function.as_deref(), Some("add"));
+                assert_eq!((expected.as_str(), found.as_str()), ("integer", "string"));
+                assert_eq!(line, Some(1));
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+
+        lunar.load("add(1)").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert!(error.to_string().ends_with("bad argument #2 to 'add' (integer expected, got no value)"));
+
+        lunar.load("add(1, 2, 3)").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert!(error.to_string().ends_with("wrong number of arguments to 'add' (2 expected, got 3)"));
+
+        lunar.load("add(1, 2^40)").unwrap();
+        let error = lunar.exec().unwrap_err();
+        assert!(error.to_string().ends_with("bad argument #2 to 'add' (value out of range for i32)"));
+    }
+
+    #[test]
+    fn call_lua_functions() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.load(
+            "
+            function add(a, b) return a + b end
+            handlers = {on_update = function(dt) ticks = (ticks or 0) + dt end}
+            function fail() error('boom') end
+            ",
+        ).unwrap();
+        lunar.exec().unwrap();
+        let top = lunar.context().stack_size();
+
+        let add: LuaFunction = lunar.get_global("add").unwrap();
+        assert_eq!(add.call::<_, i64>((2, 3)), Ok(5));
+
+        let handlers: Table = lunar.get_global("handlers").unwrap();
+        let on_update: LuaFunction = handlers.get("on_update").unwrap();
+        for _ in 0..3 {
+            on_update.call::<_, ()>(2).unwrap();
+        }
+        assert_eq!(lunar.get_global::<i64>("ticks"), Ok(6));
+
+        let swap = lunar.load_function("local a, b = ... return b, a").unwrap();
+        assert_eq!(swap.call::<_, (String, i64)>((1, "x")), Ok(("x".to_string(), 1)));
+
+        lunar.register("apply", |function: LuaFunction, value: i64| function.call::<_, i64>(value)).unwrap();
+        let double = lunar.load_function("return apply(function(x) return x * 2 end, ...)").unwrap();
+        assert_eq!(double.call::<_, i64>(21), Ok(42));
+
+        let fail: LuaFunction = lunar.get_global("fail").unwrap();
+        match fail.call::<_, ()>(()) {
+            Err(LunarError::Runtime { message, .. }) => assert_eq!(message, "boom"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        assert!(add.call::<_, String>((1, 2)).is_err());
+        assert_eq!(lunar.context().stack_size(), top);
+    }
+
+    #[test]
+    fn handles_outlive_coroutines() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        let stored = Rc::new(RefCell::new(None));
+        let store = Rc::clone(&stored);
+        lunar.register("store", move |function: LuaFunction, table: Table| {
+            *store.borrow_mut() = Some((function, table));
+        }).unwrap();
+        lunar.register("apply", |function: LuaFunction, value: i64| function.call::<_, i64>(value)).unwrap();
+        lunar.load(
+            "
+            local co = coroutine.create(function()
+                store(function(x) return x + 1 end, {answer = 42})
+                assert(apply(function(x) return x * 2 end, 21) == 42)
+                coroutine.yield()
+            end)
+            assert(coroutine.resume(co))
+            co = nil
+            -- Luau has no collectgarbage
+            if collectgarbage then collectgarbage() collectgarbage() end
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        let (function, table) = stored.borrow_mut().take().unwrap();
+        assert_eq!(function.call::<_, i64>(41), Ok(42));
+        assert_eq!(table.get::<i64>("answer"), Ok(42));
+        lunar.create_global_value("increment", function).unwrap();
+        lunar.load("assert(coroutine.wrap(function() return increment(1) end)() == 2)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(not(feature = "luau"))]
+    fn registry_entries_released() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.register("take", |_table: Table| 0).unwrap();
+        lunar.load(
+            "
+            function registry_size()
+                local n = 0
+                for _ in pairs(debug.getregistry()) do n = n + 1 end
+                return n
+            end
+            function pair() return {}, {} end
+            before = registry_size()
+            for _ = 1, 100000 do take({}) end
+            assert(registry_size() - before < 10)
+            ",
+        ).unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+
+        for _ in 0..10000 {
+            lunar.call_global_function("pair", vec![], -1).unwrap();
+            let _: Table = lunar.get_global("_G").unwrap();
+        }
+        lunar.load("assert(registry_size() - before < 10)").unwrap();
+        assert_eq!(lunar.exec(), Ok(()));
+    }
+
+    #[test]
+    fn call_global_function_results() {
+        let lunar = new_lunar();
+        lunar.load_std_library();
+        lunar.load(
+            "
+            function divmod(a, b) return math.floor(a / b), a % b end
+            function swap(a, b) return b, a end
+            function fail() error('boom') end
+            answer = 42
+            ",
+        ).unwrap();
+        lunar.exec().unwrap();
+        let top = lunar.context().stack_size();
+
+        let results = lunar.call_global_function("swap", vec![Value::String("a".into()), Value::Bool(true)], -1);
+        assert_eq!(results, Ok(vec![Value::Bool(true), Value::String("a".into())]));
+        assert_eq!(lunar.call_global::<_, (i64, i64)>("divmod", (9, 4)), Ok((2, 1)));
+        assert_eq!(lunar.call_global_function("print", vec![], -1), Ok(vec![]));
+        let results = lunar.call_global_function("swap", vec![Value::Bool(true)], 3);
+        assert_eq!(results, Ok(vec![Value::Nil, Value::Bool(true), Value::Nil]));
 
+        match lunar.call_global_function("fail", vec![], 0) {
+            Err(LunarError::Runtime { message, .. }) => assert_eq!(message, "boom"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        let error = lunar.call_global_function("answer", vec![], 0).unwrap_err();
+        assert_eq!(error.to_string(), "global 'answer' is not a function");
+        let error = lunar.call_global::<_, ()>("missing", ()).unwrap_err();
+        assert_eq!(error.to_string(), "global 'missing' is not a function");
+        assert_eq!(lunar.context().stack_size(), top);
+    }
+
+    #[test]
+    fn callback_errors_drop_locals() {
+        struct Guard(Rc<Cell<u32>>);
+
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let lunar = new_lunar();
+        lunar.load_std_library();
+
+        let drops = Rc::new(Cell::new(0));
+        let guards = Rc::clone(&drops);
+        let check = lunar.create_function(move |ctx| {
+            let _guard = Guard(Rc::clone(&guards));
+            let name: String = ctx.get(1);
+            if name.is_empty() {
+                ctx.error("empty name");
+            }
+            ctx.returns(name)
+        });
+        lunar.create_global_value("check", check).unwrap();
+
+        lunar.load(
+            "
+            assert(check('a') == 'a')
+            local ok, e = pcall(check, 1)
+            assert(not ok and tostring(e):find('string expected, got number'))
+            assert(not pcall(check, ''))
+            ",
+        ).unwrap();
         assert_eq!(lunar.exec(), Ok(()));
+        assert_eq!(drops.get(), 3);
+
+        lunar.load("check({})").unwrap();
+        match lunar.exec() {
+            Err(LunarError::TypeMismatch { position, expected, found, .. }) => {
+                assert_eq!(position, 1);
+                assert_eq!((expected.as_str(), found.as_str()), ("string", "table"));
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+        assert_eq!(drops.get(), 4);
     }
 }
@@ -1,3 +1,5 @@
+use crate::lua::*;
+
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum LuaType {
@@ -10,24 +12,51 @@ pub enum LuaType {
     Userdata,
     LightUserdata,
     Table,
+    Thread,
 }
 
 impl From<i32> for LuaType {
     fn from(value: i32) -> Self {
         match value {
-            0 => Self::Nil,
-            1 => Self::Bool,
-            2 => Self::LightUserdata,
-            3 => Self::Number,
-            4 => Self::String,
-            5 => Self::Table,
-            6 => Self::Function,
-            7 => Self::Userdata,
+            LUA_TNIL => Self::Nil,
+            LUA_TBOOLEAN => Self::Bool,
+            LUA_TLIGHTUSERDATA => Self::LightUserdata,
+            LUA_TNUMBER => Self::Number,
+            LUA_TSTRING => Self::String,
+            LUA_TTABLE => Self::Table,
+            LUA_TFUNCTION => Self::Function,
+            LUA_TUSERDATA => Self::Userdata,
+            LUA_TTHREAD => Self::Thread,
             _ => Self::Undefined,
         }
     }
 }
 
+/// A Lua number, keeping apart the integers and floats of Lua 5.3 and later.
+/// Before 5.3, Lua has only floats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+/// How typed getters, such as [`LunarContext::get`](crate::context::LunarContext::get),
+/// read an integer from a float or a float from an integer. Set per state with
+/// [`Lunar::set_number_conversion`](crate::lunar::Lunar::set_number_conversion).
+///
+/// Before Lua 5.3 every number is a float, so `Strict` reads a float without
+/// fractional part as an integer, same as `Lossless`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NumberConversion {
+    /// Integers are read from integers only, floats from floats only.
+    Strict,
+    /// The other subtype is accepted when no precision is lost: `2.0` reads as
+    /// an integer, `3` as a float, but `2.5` does not read as an integer.
+    #[default]
+    Lossless,
+    /// Floats are truncated toward zero, and integers rounded to the nearest float.
+    Truncating,
+}
 
 pub trait Type {
     fn type_of() -> LuaType;